@@ -71,6 +71,20 @@ pub fn config_hot_reload<T: for<'a> Deserialize<'a> + Sync + Send + 'static>(
     config: Arc<RwLock<T>>,
     config_path: String,
 ) -> Result<()> {
+    config_hot_reload_with(config, config_path, |_| {})
+}
+
+/// Like [`config_hot_reload`], calling `on_reload` with every newly loaded config,
+/// e.g. to apply log filter changes through `LogHandle::reload`.
+pub fn config_hot_reload_with<T, F>(
+    config: Arc<RwLock<T>>,
+    config_path: String,
+    on_reload: F,
+) -> Result<()>
+where
+    T: for<'a> Deserialize<'a> + Sync + Send + 'static,
+    F: Fn(&T) + Send + 'static,
+{
     let config_path_clone = config_path.clone();
     // reload config
     let mut watcher = RecommendedWatcher::new(
@@ -81,6 +95,7 @@ pub fn config_hot_reload<T: for<'a> Deserialize<'a> + Sync + Send + 'static>(
                 match file_config(&config_path_clone) {
                     Ok(new_config) => {
                         info!("reloading config");
+                        on_reload(&new_config);
                         *config.write() = new_config;
                     }
                     Err(error) => error!("Error reloading config: {:?}", error),
@@ -90,5 +105,7 @@ pub fn config_hot_reload<T: for<'a> Deserialize<'a> + Sync + Send + 'static>(
         notify::Config::default(),
     )?;
     watcher.watch(Path::new(&config_path), RecursiveMode::Recursive)?;
+    // dropping the watcher stops watching, keep it for the lifetime of the process
+    std::mem::forget(watcher);
     Ok(())
 }
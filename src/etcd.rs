@@ -12,23 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod discovery;
+mod election;
+#[cfg(test)]
+mod fake;
+mod lock;
+mod range;
+mod session;
+mod txn;
+mod typed;
+mod watch;
+
 use std::time::Duration;
 
 use color_eyre::{
     eyre::{eyre, OptionExt},
     Result,
 };
-use etcd_client::{Client, ConnectOptions, DeleteOptions, GetOptions, KeyValue as KV, PutOptions};
+#[cfg(feature = "etcd-tls")]
+use etcd_client::{Certificate, Identity, TlsOptions};
+use etcd_client::{
+    Client, ConnectOptions, DeleteOptions, GetOptions, KeyValue as KV, KvClientPrefix, PutOptions,
+};
 use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
-use crate::service_register::{ServiceRegister, ServiceRegisterConfig};
+use crate::{
+    codec::{Codec, Json},
+    metrics,
+    service_discovery::{instances_key, ServiceInstance},
+    service_register::{Registration, ServiceRegister, ServiceRegisterConfig},
+};
+
+pub use election::{Election, Leadership};
+pub use etcd_client::{Compare, CompareOp, SortOrder};
+pub use lock::EtcdLock;
+pub use range::{EtcdRange, RangePage, RangeStream};
+pub use session::EtcdSession;
+pub use txn::{EtcdTxn, TxnOutput, TxnResult};
+pub use watch::{WatchEvent, WatchStream};
 
 pub type KeyValue = KV;
 
 #[derive(Clone)]
 pub struct Etcd {
+    /// raw client, keys passed to it are not namespaced
     pub client: Client,
+    namespace: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +67,14 @@ pub struct EtcdConfig {
     pub endpoints: Vec<String>,
     pub timeout: u64,
     pub keep_alive: u64,
+    /// user for authentication, requires `password`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[cfg(feature = "etcd-tls")]
+    pub tls: Option<EtcdTlsConfig>,
+    /// prepended to all keys, lock and election names, and stripped from the
+    /// keys returned, e.g. `staging/` to share a cluster between environments
+    pub namespace: String,
 }
 
 impl Default for EtcdConfig {
@@ -45,30 +83,123 @@ impl Default for EtcdConfig {
             endpoints: vec!["http://127.0.0.1:2379".to_owned()],
             timeout: 2000,
             keep_alive: 300,
+            username: Default::default(),
+            password: Default::default(),
+            #[cfg(feature = "etcd-tls")]
+            tls: Default::default(),
+            namespace: Default::default(),
+        }
+    }
+}
+
+/// TLS for all `endpoints`, `http://` endpoints are switched to `https://`.
+#[cfg(feature = "etcd-tls")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EtcdTlsConfig {
+    /// PEM CA certificate file
+    pub ca_cert: Option<String>,
+    /// PEM client certificate file for mutual TLS
+    pub client_cert: Option<String>,
+    /// PEM client key file for mutual TLS
+    pub client_key: Option<String>,
+    /// name verified against the server certificate, defaults to the endpoint host
+    pub domain: Option<String>,
+}
+
+#[cfg(feature = "etcd-tls")]
+impl EtcdTlsConfig {
+    fn options(&self) -> Result<TlsOptions> {
+        let read = |path: &String| {
+            std::fs::read(path).map_err(|e| eyre!("read etcd tls file `{path}` failed: {e}"))
+        };
+        let mut options = TlsOptions::new();
+        if let Some(ca_cert) = &self.ca_cert {
+            options = options.ca_certificate(Certificate::from_pem(read(ca_cert)?));
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                options =
+                    options.identity(Identity::from_pem(read(client_cert)?, read(client_key)?));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(eyre!(
+                    "etcd tls client_cert and client_key must be set together"
+                ))
+            }
+        }
+        if let Some(domain) = &self.domain {
+            options = options.domain_name(domain);
         }
+        Ok(options)
     }
 }
 
 impl Etcd {
     pub async fn new(config: &EtcdConfig) -> Result<Self> {
-        let client = Client::connect(
-            &config.endpoints,
-            Some(
-                ConnectOptions::new()
-                    .with_connect_timeout(Duration::from_millis(config.timeout))
-                    .with_keep_alive(
-                        Duration::from_secs(config.keep_alive),
-                        Duration::from_millis(config.timeout),
-                    )
-                    .with_keep_alive_while_idle(true)
-                    .with_timeout(Duration::from_millis(config.timeout)),
-            ),
-        )
-        .await
-        .map_err(|e| eyre!("etcd connect failed: {e}"))?;
-        Ok(Self { client })
+        let mut options = ConnectOptions::new()
+            .with_connect_timeout(Duration::from_millis(config.timeout))
+            .with_keep_alive(
+                Duration::from_secs(config.keep_alive),
+                Duration::from_millis(config.timeout),
+            )
+            .with_keep_alive_while_idle(true)
+            .with_timeout(Duration::from_millis(config.timeout));
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                options = options.with_user(username, password);
+            }
+            (None, None) => {}
+            _ => return Err(eyre!("etcd username and password must be set together")),
+        }
+        #[allow(unused_mut)]
+        let mut endpoints = config.endpoints.clone();
+        #[cfg(feature = "etcd-tls")]
+        if let Some(tls) = &config.tls {
+            options = options.with_tls(tls.options()?);
+            // etcd-client rejects `http://` endpoints once TLS is set
+            endpoints = endpoints
+                .into_iter()
+                .map(|endpoint| match endpoint.strip_prefix("http://") {
+                    Some(address) => format!("https://{address}"),
+                    None => endpoint,
+                })
+                .collect();
+        }
+        let client = Client::connect(&endpoints, Some(options))
+            .await
+            .map_err(|e| eyre!("etcd connect failed: {e}"))?;
+        Ok(Self {
+            client,
+            namespace: config.namespace.clone().into_bytes(),
+        })
+    }
+
+    /// Key-value client which prepends the namespace to keys and strips it
+    /// from the keys returned.
+    pub fn kv(&self) -> KvClientPrefix {
+        KvClientPrefix::new(self.client.kv_client(), self.namespace.clone())
     }
 
+    pub(crate) fn namespaced(&self, key: impl Into<Vec<u8>>) -> Vec<u8> {
+        let key = key.into();
+        if self.namespace.is_empty() {
+            return key;
+        }
+        [self.namespace.as_slice(), &key].concat()
+    }
+
+    pub(crate) fn strip_namespace(&self, key: &[u8]) -> Vec<u8> {
+        key.strip_prefix(self.namespace.as_slice())
+            .unwrap_or(key)
+            .to_vec()
+    }
+
+    /// Write `key`, expiring after `ttl` seconds unless it is 0.
+    ///
+    /// Every write with a ttl grants a new lease, use an [`EtcdSession`] for
+    /// keys which should live as long as the process.
     pub async fn put(
         &self,
         key: impl Into<Vec<u8>>,
@@ -79,95 +210,103 @@ impl Etcd {
         let option = if ttl == 0 {
             PutOptions::new().with_prev_key()
         } else {
-            let lease = client
-                .lease_grant(ttl, None)
+            let lease = metrics::observe("etcd", "lease_grant", client.lease_grant(ttl, None))
                 .await
                 .map_err(|e| eyre!("etcd lease_grant failed: {e}"))?;
             PutOptions::new().with_lease(lease.id()).with_prev_key()
         };
-        let put_rsp = client
-            .put(key, value, Some(option))
+        let put_rsp = metrics::observe("etcd", "put", self.kv().put(key, value, Some(option)))
             .await
             .map_err(|e| eyre!("etcd put failed: {e}"))?;
         Ok(put_rsp.prev_key().cloned())
     }
 
     pub async fn get(&self, key: impl Into<Vec<u8>>) -> Result<KeyValue> {
-        self.client
-            .to_owned()
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-            .cloned()
-            .ok_or_eyre("data not found")
+        let mut kv = self.kv();
+        metrics::observe(
+            "etcd",
+            "get",
+            kv.get(key, Some(GetOptions::new().with_limit(1))),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .first()
+        .cloned()
+        .ok_or_eyre("data not found")
     }
 
+    /// All keys starting with `key` read with a single request, use
+    /// [`Self::range`] for large prefixes.
     pub async fn get_with_prefix(&self, key: impl Into<Vec<u8>>) -> Result<Vec<KeyValue>> {
-        Ok(self
-            .client
-            .to_owned()
-            .get(key, Some(GetOptions::new().with_prefix()))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .to_vec())
+        let mut kv = self.kv();
+        Ok(metrics::observe(
+            "etcd",
+            "get_prefix",
+            kv.get(key, Some(GetOptions::new().with_prefix())),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .to_vec())
     }
 
     pub async fn delete(&self, key: impl Into<Vec<u8>>) -> Result<i64> {
-        Ok(self
-            .client
-            .to_owned()
-            .delete(key, None)
+        let mut kv = self.kv();
+        Ok(metrics::observe("etcd", "delete", kv.delete(key, None))
             .await
             .map_err(|e| eyre!("etcd delete failed: {e}"))?
             .deleted())
     }
 
     pub async fn delete_with_prefix(&self, key: impl Into<Vec<u8>>) -> Result<i64> {
-        Ok(self
-            .client
-            .to_owned()
-            .delete(key, Some(DeleteOptions::new().with_prefix()))
-            .await
-            .map_err(|e| eyre!("etcd delete failed: {e}"))?
-            .deleted())
+        let mut kv = self.kv();
+        Ok(metrics::observe(
+            "etcd",
+            "delete_prefix",
+            kv.delete(key, Some(DeleteOptions::new().with_prefix())),
+        )
+        .await
+        .map_err(|e| eyre!("etcd delete failed: {e}"))?
+        .deleted())
     }
 
+    /// Renew the lease of `key` once, keys without a lease are left as they are.
     pub async fn touch(&self, key: impl Into<Vec<u8>>) -> Result<()> {
         let mut client = self.client.clone();
-        let lease = client
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-            .map(|kv| kv.lease())
-            .unwrap_or(0);
+        let lease = metrics::observe(
+            "etcd",
+            "get",
+            self.kv().get(key, Some(GetOptions::new().with_limit(1))),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .first()
+        .map(|kv| kv.lease())
+        .unwrap_or(0);
         if lease != 0 {
-            client
-                .lease_keep_alive(lease)
-                .await
-                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
+            session::keep_alive_once(&mut client, lease).await?;
         }
         Ok(())
     }
 
+    /// Renew the lease of `key`, or write it with a new lease of `ttl` seconds
+    /// if it is missing, has no lease or the lease expired.
     pub async fn put_or_touch(&self, key: &str, value: impl Into<Vec<u8>>, ttl: i64) -> Result<()> {
         let mut client = self.client.clone();
-        if let Some(prev) = client
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-        {
-            client
-                .lease_keep_alive(prev.lease())
-                .await
-                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
-        } else {
+        let lease = metrics::observe(
+            "etcd",
+            "get",
+            self.kv().get(key, Some(GetOptions::new().with_limit(1))),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .first()
+        .map(|kv| kv.lease())
+        .unwrap_or(0);
+        if lease == 0 || session::keep_alive_once(&mut client, lease).await? <= 0 {
             self.put(key, value, ttl).await?;
         }
         Ok(())
@@ -177,60 +316,423 @@ impl Etcd {
         &self,
         service_name: &str,
         config: ServiceRegisterConfig,
-    ) -> Result<()> {
+    ) -> Result<Registration> {
         self.keep_service_register(service_name, config).await
     }
 }
 
+impl Etcd {
+    /// Write the keys of this replica attached to a new session of `config.ttl` seconds.
+    async fn register(
+        &self,
+        service_name: &str,
+        instance_id: &str,
+        config: &ServiceRegisterConfig,
+    ) -> Result<EtcdSession> {
+        let session = self.session(config.ttl).await?;
+        session
+            .put(
+                format!(
+                    "traefik/http/services/{}/loadbalancer/servers/{}/url",
+                    service_name, instance_id
+                ),
+                config.url.clone(),
+            )
+            .await?;
+        let instance = ServiceInstance {
+            id: instance_id.to_owned(),
+            url: config.url.clone(),
+            tags: config.tags.clone(),
+        };
+        session
+            .put(
+                format!("{}/{}", instances_key(service_name), instance.id),
+                Json::encode(&instance)?,
+            )
+            .await?;
+        self.register_routes(service_name, config, session.lease_id())
+            .await?;
+        Ok(session)
+    }
+
+    /// Write the keys shared by all replicas attached to the lease of this one.
+    ///
+    /// Every replica writes them again on each heartbeat, so they outlive the
+    /// replica which wrote them last and are gone once no replica is left,
+    /// also if the last one is gone without deregistering.
+    async fn register_routes(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+        lease: i64,
+    ) -> Result<()> {
+        let mut txn = self.txn();
+        for (key, value) in routes(service_name, config) {
+            txn = txn.then_put_with_lease(key, value, lease);
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Delete the keys of this replica, and the keys shared by all replicas if
+    /// no other replica is registered, otherwise attach those to the lease of
+    /// another replica so revoking this one keeps them.
+    async fn deregister(
+        &self,
+        service_name: &str,
+        instance_id: &str,
+        config: &ServiceRegisterConfig,
+        session: EtcdSession,
+    ) -> Result<()> {
+        let instances = format!("{}/", instances_key(service_name));
+        self.delete(format!("{instances}{instance_id}")).await?;
+        let routes = routes(service_name, config);
+        loop {
+            // holds if no key has the prefix, also against a replica starting meanwhile
+            let mut txn = self.txn().when(
+                Compare::create_revision(instances.clone(), CompareOp::Equal, 0).with_prefix(),
+            );
+            for (key, _) in &routes {
+                txn = txn.then_delete(key.clone());
+            }
+            let result = txn.else_get_with_prefix(instances.clone()).commit().await?;
+            if result.succeeded {
+                break;
+            }
+            let Some(other) = result.kvs().into_iter().find(|kv| kv.lease() != 0) else {
+                break;
+            };
+            // fails if the other replica deregistered meanwhile
+            let mut txn = self.txn().when(Compare::lease(
+                other.key().to_vec(),
+                CompareOp::Equal,
+                other.lease(),
+            ));
+            for (key, value) in &routes {
+                txn = txn.then_put_with_lease(key.clone(), value.clone(), other.lease());
+            }
+            if txn.commit().await?.succeeded {
+                break;
+            }
+        }
+        // revoking the lease deletes the server key of this replica
+        session.revoke().await
+    }
+}
+
+/// The router of the service and the key value pairs of `config.tags`.
+fn routes(service_name: &str, config: &ServiceRegisterConfig) -> Vec<(String, String)> {
+    std::iter::once((
+        format!("traefik/http/routers/{}/service", service_name),
+        service_name.to_owned(),
+    ))
+    .chain(config.tags.iter().map(|tag| {
+        let (key, value) = tag.split_once('=').unwrap_or_default();
+        (key.to_owned(), value.to_owned())
+    }))
+    .collect()
+}
+
 impl ServiceRegister for Etcd {
     async fn keep_service_register(
         &self,
         service_name: &str,
         config: ServiceRegisterConfig,
-    ) -> Result<()> {
-        info!("keep_service_register: {config:?}");
+    ) -> Result<Registration> {
+        let instance_id = config.instance_id(service_name);
+        info!("keep_service_register: {instance_id} {config:?}");
         let mut keep_alive_interval =
             tokio::time::interval(tokio::time::Duration::from_secs((config.ttl / 2) as u64));
 
         let etcd = self.clone();
         let service_name = service_name.to_owned();
-        tokio::spawn(async move {
+        let (stop_tx, mut stop) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move {
+            let mut session: Option<EtcdSession> = None;
             loop {
-                keep_alive_interval.tick().await;
-                let tags = config.tags.clone();
-                let service_name = service_name.clone();
-
-                if let Err(e) = etcd
-                    .put_or_touch(
-                        &format!(
-                            "traefik/http/services/{}/loadbalancer/servers/{}/url",
-                            service_name, service_name
-                        ),
-                        config.url.clone(),
-                        config.ttl,
-                    )
-                    .await
-                {
-                    error!("keep_service_register failed: {:?}", e);
+                tokio::select! {
+                    _ = keep_alive_interval.tick() => {}
+                    Ok(_) = stop.wait_for(|stop| *stop) => break,
                 }
-                if let Err(e) = etcd
-                    .put_or_touch(
-                        &format!("traefik/http/routers/{}/service", service_name),
-                        service_name,
-                        config.ttl,
-                    )
-                    .await
-                {
-                    error!("keep_service_register failed: {:?}", e);
-                }
-                for tag in tags {
-                    let (key, value) = tag.split_once('=').unwrap_or_default();
-                    if let Err(e) = etcd.put_or_touch(key, value, config.ttl).await {
-                        error!("keep_service_register failed: {:?}", e);
+                // the session renews the lease, register again once it is lost
+                let ok = match &session {
+                    Some(session) if !session.is_lost() => {
+                        match etcd
+                            .register_routes(&service_name, &config, session.lease_id())
+                            .await
+                        {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error!("keep_service_register failed: {:?}", e);
+                                false
+                            }
+                        }
                     }
+                    _ => match etcd.register(&service_name, &instance_id, &config).await {
+                        Ok(new_session) => {
+                            session = Some(new_session);
+                            true
+                        }
+                        Err(e) => {
+                            error!("keep_service_register failed: {:?}", e);
+                            session = None;
+                            false
+                        }
+                    },
+                };
+                metrics::heartbeat("etcd", ok);
+            }
+            info!("deregister: {instance_id}");
+            match session {
+                Some(session) if !session.is_lost() => {
+                    etcd.deregister(&service_name, &instance_id, &config, session)
+                        .await
                 }
+                // the keys of this replica expired with its lease
+                _ => Ok(()),
             }
         });
-        Ok(())
+        Ok(Registration::new(async move {
+            stop_tx.send_replace(true);
+            task.await
+                .map_err(|e| eyre!("keep_service_register task failed: {e}"))?
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures_core::Stream;
+
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    async fn connect(url: &str, namespace: &str) -> Etcd {
+        let config = EtcdConfig {
+            endpoints: vec![url.to_owned()],
+            namespace: namespace.to_owned(),
+            ..Default::default()
+        };
+        Etcd::new(&config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn put_or_touch_renews_the_lease() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put_or_touch("key", "1", 10).await.unwrap();
+        let written = etcd.get("key").await.unwrap();
+        assert_ne!(written.lease(), 0);
+        etcd.put_or_touch("key", "2", 10).await.unwrap();
+        let touched = etcd.get("key").await.unwrap();
+        assert_eq!(touched.lease(), written.lease());
+        assert_eq!(touched.value(), b"1");
+    }
+
+    #[tokio::test]
+    async fn put_or_touch_grants_a_new_lease_after_expiry() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put_or_touch("key", "1", 10).await.unwrap();
+        let expired = etcd.get("key").await.unwrap().lease();
+        fake.expire_lease(expired);
+        assert!(fake.keys("key").is_empty());
+        etcd.put_or_touch("key", "2", 10).await.unwrap();
+        let written = etcd.get("key").await.unwrap();
+        assert_ne!(written.lease(), expired);
+        assert_eq!(written.value(), b"2");
+    }
+
+    #[tokio::test]
+    async fn touch_leaves_keys_without_lease() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("key", "1", 0).await.unwrap();
+        etcd.touch("key").await.unwrap();
+        etcd.put_or_touch("key", "2", 10).await.unwrap();
+        let written = etcd.get("key").await.unwrap();
+        assert_ne!(written.lease(), 0);
+        assert_eq!(written.value(), b"2");
+    }
+
+    #[tokio::test]
+    async fn namespace_isolates_keys() {
+        let (fake, url) = FakeEtcd::start().await;
+        let a = connect(&url, "a/").await;
+        let b = connect(&url, "b/").await;
+        let mut watch = pin!(a.watch_prefix("svc/").await.unwrap());
+        a.put("svc/x", "a", 0).await.unwrap();
+        b.put("svc/x", "b", 0).await.unwrap();
+
+        let kvs = a.get_with_prefix("svc/").await.unwrap();
+        assert_eq!(kvs.len(), 1);
+        assert_eq!((kvs[0].key(), kvs[0].value()), (&b"svc/x"[..], &b"a"[..]));
+        let page = a.range("svc/").next_page().await.unwrap().unwrap();
+        assert_eq!(page.kvs.len(), 1);
+        assert_eq!(page.kvs[0].key(), b"svc/x");
+        assert_eq!(a.count_with_prefix("svc/").await.unwrap(), 1);
+        assert_eq!(a.keys_with_prefix("").await.unwrap(), [b"svc/x".to_vec()]);
+
+        assert!(
+            a.compare_and_swap("svc/x", "a", "a2")
+                .await
+                .unwrap()
+                .succeeded
+        );
+        assert!(
+            !b.compare_and_swap("svc/x", "a", "b2")
+                .await
+                .unwrap()
+                .succeeded
+        );
+        // compares over a prefix only see keys of the namespace
+        let result = a
+            .txn()
+            .when(Compare::value("svc/", CompareOp::NotEqual, "b").with_prefix())
+            .then_put("svc/y", "a")
+            .commit()
+            .await
+            .unwrap();
+        assert!(result.succeeded);
+
+        let timeout = Duration::from_secs(2);
+        let lock_a = tokio::time::timeout(timeout, a.lock("job", 10))
+            .await
+            .unwrap()
+            .unwrap();
+        let lock_b = tokio::time::timeout(timeout, b.lock("job", 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(lock_a.key().starts_with(b"job/"));
+        assert!(lock_b.key().starts_with(b"job/"));
+        let _leader_a = tokio::time::timeout(timeout, a.election("leader", 10).campaign("a"))
+            .await
+            .unwrap()
+            .unwrap();
+        let _leader_b = tokio::time::timeout(timeout, b.election("leader", 10).campaign("b"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            a.election("leader", 10).leader().await.unwrap(),
+            Some(b"a".to_vec())
+        );
+
+        for (key, value) in [("svc/x", "a"), ("svc/x", "a2"), ("svc/y", "a")] {
+            let event = tokio::time::timeout(
+                timeout,
+                std::future::poll_fn(|cx| watch.as_mut().poll_next(cx)),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert!(
+                matches!(&event, WatchEvent::Put { key: k, value: v, .. }
+                    if k == key.as_bytes() && v == value.as_bytes()),
+                "{event:?}"
+            );
+        }
+
+        assert_eq!(b.delete_with_prefix("svc/").await.unwrap(), 1);
+        assert_eq!(a.count_with_prefix("svc/").await.unwrap(), 2);
+        let keys = fake.keys("");
+        assert!(
+            keys.iter()
+                .all(|key| key.starts_with("a/") || key.starts_with("b/")),
+            "{keys:?}"
+        );
+        assert!(keys.iter().any(|key| key.starts_with("a/job/")));
+        assert!(keys.iter().any(|key| key.starts_with("b/leader/")));
+    }
+
+    #[cfg(feature = "etcd-tls")]
+    #[tokio::test]
+    async fn tls_switches_http_endpoints_to_https() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.pem();
+        let (_fake, url) = FakeEtcd::start_tls(&cert, &certified.key_pair.serialize_pem()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let ca_cert = dir.path().join("ca.pem");
+        std::fs::write(&ca_cert, cert).unwrap();
+        let tls = EtcdTlsConfig {
+            ca_cert: Some(ca_cert.to_str().unwrap().to_owned()),
+            domain: Some("localhost".to_owned()),
+            ..Default::default()
+        };
+        for endpoint in [url.clone(), url.replace("http://", "https://")] {
+            let config = EtcdConfig {
+                endpoints: vec![endpoint.clone()],
+                tls: Some(tls.clone()),
+                ..Default::default()
+            };
+            let etcd = Etcd::new(&config).await.unwrap();
+            etcd.put("key", endpoint.as_str(), 0).await.unwrap();
+            assert_eq!(etcd.get("key").await.unwrap().value(), endpoint.as_bytes());
+        }
+    }
+
+    const SERVERS: &str = "traefik/http/services/service/loadbalancer/servers/";
+    const ROUTER: &str = "traefik/http/routers/service/service";
+    const RULE: &str = "traefik/http/routers/service/rule";
+
+    async fn wait_for_servers(fake: &FakeEtcd, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let servers = fake.keys(SERVERS);
+            if servers.len() == count {
+                return servers;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("expected {count} servers, got {:?}", fake.keys(SERVERS));
+    }
+
+    #[tokio::test]
+    async fn routes_follow_the_registered_replicas() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let mut registrations = Vec::new();
+        for id in ["a", "b"] {
+            let config = ServiceRegisterConfig {
+                url: format!("http://{id}:8080"),
+                tags: vec![format!("{RULE}=Host(`service`)")],
+                ttl: 10,
+                instance_id: Some(id.to_owned()),
+            };
+            registrations.push(etcd.keep_service_register("service", config).await.unwrap());
+            wait_for_servers(&fake, registrations.len()).await;
+        }
+        assert_eq!(etcd.get(ROUTER).await.unwrap().value(), b"service");
+        assert_eq!(etcd.get(RULE).await.unwrap().value(), b"Host(`service`)");
+
+        // the routes move to the lease of the replica left
+        registrations.remove(0).deregister().await.unwrap();
+        assert_eq!(
+            wait_for_servers(&fake, 1).await,
+            [format!("{SERVERS}b/url")]
+        );
+        let lease = etcd.get(format!("{SERVERS}b/url")).await.unwrap().lease();
+        assert_eq!(etcd.get(ROUTER).await.unwrap().lease(), lease);
+        assert_eq!(etcd.get(RULE).await.unwrap().lease(), lease);
+
+        // and expire with the last replica even without deregistering
+        fake.expire_lease(lease);
+        assert!(fake.keys(SERVERS).is_empty());
+        assert!(fake.keys("traefik/http/routers/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn last_replica_deletes_the_routes() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let config = ServiceRegisterConfig {
+            url: "http://a:8080".to_owned(),
+            ttl: 10,
+            instance_id: Some("a".to_owned()),
+            ..Default::default()
+        };
+        let registration = etcd.keep_service_register("service", config).await.unwrap();
+        wait_for_servers(&fake, 1).await;
+        assert_eq!(fake.keys(ROUTER), [ROUTER]);
+        registration.deregister().await.unwrap();
+        assert!(fake.keys("traefik/").is_empty());
+        assert!(fake.keys("services/").is_empty());
     }
 }
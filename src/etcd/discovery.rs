@@ -0,0 +1,91 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, pin::Pin};
+
+use color_eyre::Result;
+use futures_core::Stream;
+use tokio::sync::watch;
+use tracing::warn;
+
+use super::{Etcd, WatchEvent};
+use crate::{
+    codec::{Codec, Json},
+    service_discovery::{instances_key, ServiceDiscovery, ServiceInstance},
+};
+
+impl Etcd {
+    /// Instances of `service_name` by key.
+    async fn instances(&self, service_name: &str) -> Result<BTreeMap<Vec<u8>, ServiceInstance>> {
+        let mut range = self.range(format!("{}/", instances_key(service_name)));
+        let mut instances = BTreeMap::new();
+        while let Some(page) = range.next_page().await? {
+            for kv in page.kvs {
+                if let Some(instance) = decode(kv.key(), kv.value()) {
+                    instances.insert(kv.key().to_vec(), instance);
+                }
+            }
+        }
+        Ok(instances)
+    }
+}
+
+impl ServiceDiscovery for Etcd {
+    async fn resolve(&self, service_name: &str) -> Result<Vec<ServiceInstance>> {
+        Ok(self.instances(service_name).await?.into_values().collect())
+    }
+
+    /// Note that [`Etcd::watch`] takes precedence in method calls, call this
+    /// as `ServiceDiscovery::watch(&etcd, service_name)`.
+    async fn watch(&self, service_name: &str) -> Result<watch::Receiver<Vec<ServiceInstance>>> {
+        // watch before reading, changes in between are applied afterwards
+        let mut events = self
+            .watch_prefix(format!("{}/", instances_key(service_name)))
+            .await?;
+        let mut instances = self.instances(service_name).await?;
+        let (instances_tx, instances_rx) = watch::channel(instances.values().cloned().collect());
+        tokio::spawn(async move {
+            loop {
+                let next = std::future::poll_fn(|cx| Pin::new(&mut events).poll_next(cx));
+                let event = tokio::select! {
+                    _ = instances_tx.closed() => return,
+                    event = next => event,
+                };
+                let changed = match event {
+                    Some(WatchEvent::Put { key, value, .. }) => match decode(&key, &value) {
+                        Some(instance) => instances.insert(key, instance.clone()) != Some(instance),
+                        None => instances.remove(&key).is_some(),
+                    },
+                    Some(WatchEvent::Delete { key, .. }) => instances.remove(&key).is_some(),
+                    None => return,
+                };
+                if changed {
+                    instances_tx.send_replace(instances.values().cloned().collect());
+                }
+            }
+        });
+        Ok(instances_rx)
+    }
+}
+
+fn decode(key: &[u8], value: &[u8]) -> Option<ServiceInstance> {
+    Json::decode(value)
+        .inspect_err(|e| {
+            warn!(
+                "skip service instance `{}`: {e}",
+                String::from_utf8_lossy(key)
+            )
+        })
+        .ok()
+}
@@ -0,0 +1,245 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{Client, Error, LeaderKey, ProclaimOptions, ResignOptions};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::{session::EtcdSession, Etcd};
+use crate::metrics;
+
+/// Leader election among the processes campaigning under the same name.
+#[derive(Clone)]
+pub struct Election {
+    etcd: Etcd,
+    /// name with the namespace prepended
+    name: Vec<u8>,
+    ttl: i64,
+}
+
+impl Etcd {
+    /// Election `name`, leadership is bound to a session lease of `ttl` seconds.
+    pub fn election(&self, name: impl Into<Vec<u8>>, ttl: i64) -> Election {
+        Election {
+            etcd: self.clone(),
+            name: self.namespaced(name),
+            ttl,
+        }
+    }
+}
+
+impl Election {
+    /// Wait until elected, then announce `value` as the leader value.
+    ///
+    /// Leadership is kept until [`Leadership::resign`], dropping the returned
+    /// [`Leadership`] or the session lease expires.
+    pub async fn campaign(&self, value: impl Into<Vec<u8>>) -> Result<Leadership> {
+        let mut client = self.etcd.client.clone();
+        let session = self.etcd.session(self.ttl).await?;
+        let leader = metrics::observe(
+            "etcd",
+            "campaign",
+            client.campaign(self.name.clone(), value, session.lease_id()),
+        )
+        .await
+        .map_err(|e| eyre!("etcd campaign failed: {e}"))?
+        .take_leader()
+        .ok_or_else(|| eyre!("etcd campaign failed: no leader key"))?;
+        info!(
+            "etcd election `{}` won",
+            String::from_utf8_lossy(&self.name)
+        );
+        Ok(Leadership {
+            client,
+            leader,
+            session,
+        })
+    }
+
+    /// The value of the current leader, if any.
+    pub async fn leader(&self) -> Result<Option<Vec<u8>>> {
+        let mut client = self.etcd.client.clone();
+        match metrics::observe("etcd", "leader", client.leader(self.name.clone())).await {
+            Ok(response) => Ok(response.kv().map(|kv| kv.value().to_vec())),
+            Err(Error::GRpcStatus(status)) if status.message().contains("no leader") => Ok(None),
+            Err(e) => Err(eyre!("etcd leader failed: {e}")),
+        }
+    }
+
+    /// Receive every change of the leader value.
+    ///
+    /// The current leader is reported first, a value proclaimed by the leader
+    /// counts as a change. A resigned leader stays reported until the next
+    /// one is elected. Observing stops once all receivers are dropped.
+    pub fn observe(&self) -> watch::Receiver<Option<Vec<u8>>> {
+        let (leader_tx, leader) = watch::channel(None);
+        let mut client = self.etcd.client.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            loop {
+                let observe = async {
+                    let mut stream = client
+                        .observe(name.clone())
+                        .await
+                        .map_err(|e| eyre!("etcd observe failed: {e}"))?;
+                    while let Some(response) = stream
+                        .message()
+                        .await
+                        .map_err(|e| eyre!("etcd observe failed: {e}"))?
+                    {
+                        leader_tx.send_replace(response.kv().map(|kv| kv.value().to_vec()));
+                    }
+                    Err::<(), _>(eyre!("etcd observe stream closed"))
+                };
+                tokio::select! {
+                    _ = leader_tx.closed() => return,
+                    Err(e) = observe => warn!("{e}"),
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        leader
+    }
+}
+
+/// Leadership won by [`Election::campaign`], resigned when dropped.
+pub struct Leadership {
+    client: Client,
+    leader: LeaderKey,
+    session: EtcdSession,
+}
+
+impl Leadership {
+    /// Replace the leader value without another election.
+    pub async fn proclaim(&self, value: impl Into<Vec<u8>>) -> Result<()> {
+        let mut client = self.client.clone();
+        metrics::observe(
+            "etcd",
+            "proclaim",
+            client.proclaim(
+                value,
+                Some(ProclaimOptions::new().with_leader(self.leader.clone())),
+            ),
+        )
+        .await
+        .map_err(|e| eyre!("etcd proclaim failed: {e}"))?;
+        Ok(())
+    }
+
+    /// Give up leadership so another campaigner gets elected.
+    pub async fn resign(mut self) -> Result<()> {
+        metrics::observe(
+            "etcd",
+            "resign",
+            self.client
+                .resign(Some(ResignOptions::new().with_leader(self.leader.clone()))),
+        )
+        .await
+        .map_err(|e| eyre!("etcd resign failed: {e}"))?;
+        self.session.revoke().await
+    }
+
+    /// Whether the session lease expired and another process may have been elected.
+    pub fn is_lost(&self) -> bool {
+        self.session.is_lost()
+    }
+
+    /// Wait until leadership is lost, e.g. to stop singleton tasks.
+    pub async fn lost(&self) {
+        self.session.lost().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[tokio::test]
+    async fn campaign_and_resign() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let election = etcd.election("leader", 10);
+        assert_eq!(election.leader().await.unwrap(), None);
+        let leadership = election.campaign("a").await.unwrap();
+        assert_eq!(election.leader().await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(fake.keys("leader/").len(), 1);
+        leadership.resign().await.unwrap();
+        assert_eq!(election.leader().await.unwrap(), None);
+        assert!(fake.keys("leader/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn campaign_waits_for_resign() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        let election = etcd.election("leader", 10);
+        let leadership = election.campaign("a").await.unwrap();
+        let waiting = tokio::spawn({
+            let election = election.clone();
+            async move { election.campaign("b").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(election.leader().await.unwrap(), Some(b"a".to_vec()));
+        leadership.resign().await.unwrap();
+        let _leadership = tokio::time::timeout(TIMEOUT, waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(election.leader().await.unwrap(), Some(b"b".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn observe_follows_leader() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        let election = etcd.election("leader", 10);
+        let mut observed = election.observe();
+        let changed = |observed: &mut watch::Receiver<Option<Vec<u8>>>, value: &'static [u8]| {
+            let mut observed = observed.clone();
+            async move {
+                tokio::time::timeout(TIMEOUT, observed.wait_for(|v| v.as_deref() == Some(value)))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+        };
+        let a = election.campaign("a").await.unwrap();
+        changed(&mut observed, b"a").await;
+        a.proclaim("a2").await.unwrap();
+        changed(&mut observed, b"a2").await;
+        a.resign().await.unwrap();
+        let _b = election.campaign("b").await.unwrap();
+        changed(&mut observed, b"b").await;
+    }
+
+    #[tokio::test]
+    async fn leadership_lost_on_lease_expiry() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let election = etcd.election("leader", 1);
+        let leadership = election.campaign("a").await.unwrap();
+        fake.expire_lease(leadership.session.lease_id());
+        tokio::time::timeout(TIMEOUT, leadership.lost())
+            .await
+            .unwrap();
+        assert!(leadership.is_lost());
+        assert_eq!(election.leader().await.unwrap(), None);
+        // proclaiming needs the leader key
+        assert!(leadership.proclaim("late").await.is_err());
+    }
+}
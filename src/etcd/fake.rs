@@ -0,0 +1,1056 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-process stand-in for an etcd server in tests, serves the KV, watch,
+//! lease, lock and election APIs over gRPC from an in-memory store which
+//! keeps the history of revisions like etcd's MVCC store.
+
+// handlers fail with `Status` like generated tonic services
+#![allow(clippy::result_large_err)]
+
+mod pb;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+};
+use tonic::{
+    body::BoxBody,
+    codec::{ProstCodec, Streaming},
+    codegen::{
+        http,
+        tokio_stream::wrappers::{ReceiverStream, TcpListenerStream},
+        BoxFuture, Service,
+    },
+    server::{Grpc, NamedService, ServerStreamingService, StreamingService, UnaryService},
+    transport::Server,
+    Status,
+};
+
+use pb::*;
+
+use super::{Etcd, EtcdConfig};
+
+struct Lease {
+    ttl: i64,
+    expires: Instant,
+}
+
+struct Store {
+    /// revision of the last write
+    revision: i64,
+    /// revisions before this one are gone
+    compacted: i64,
+    kvs: BTreeMap<Vec<u8>, KeyValue>,
+    /// events since `compacted` with their revision, the previous key value
+    /// is always kept to read at earlier revisions
+    history: Vec<(i64, Event)>,
+    leases: HashMap<i64, Lease>,
+    next_lease: i64,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            revision: 1,
+            compacted: 0,
+            kvs: BTreeMap::new(),
+            history: Vec::new(),
+            leases: HashMap::new(),
+            next_lease: 0x1000,
+        }
+    }
+}
+
+/// Whether `key` is in the range of a request, a single key if `end` is
+/// empty, all keys from `start` on if it is `\0`.
+fn in_range(key: &[u8], start: &[u8], end: &[u8]) -> bool {
+    match end {
+        [] => key == start,
+        [0] => key >= start,
+        end => key >= start && key < end,
+    }
+}
+
+impl Store {
+    /// Header with the revision of the writes of the running request, if any.
+    fn header(&self) -> Option<ResponseHeader> {
+        let revision = self
+            .history
+            .last()
+            .map_or(self.revision, |(revision, _)| self.revision.max(*revision));
+        Some(ResponseHeader {
+            cluster_id: 1,
+            member_id: 1,
+            revision,
+            raft_term: 1,
+        })
+    }
+
+    /// Revision of the writes of the running request, all of them share it.
+    const fn next_revision(&self) -> i64 {
+        self.revision + 1
+    }
+
+    /// End the running request, returns whether it wrote anything.
+    fn finish(&mut self) -> bool {
+        let wrote = self
+            .history
+            .last()
+            .is_some_and(|(revision, _)| *revision > self.revision);
+        if wrote {
+            self.revision += 1;
+        }
+        wrote
+    }
+
+    /// The keys at `revision`, undoing the later events.
+    fn kvs_at(&self, revision: i64) -> Result<BTreeMap<Vec<u8>, KeyValue>, Status> {
+        if revision > self.revision {
+            return Err(Status::out_of_range(
+                "etcdserver: mvcc: required revision is a future revision",
+            ));
+        }
+        if revision > 0 && revision < self.compacted {
+            return Err(Status::out_of_range(
+                "etcdserver: mvcc: required revision has been compacted",
+            ));
+        }
+        let mut kvs = self.kvs.clone();
+        if revision > 0 {
+            for (_, event) in self.history.iter().rev().take_while(|(r, _)| *r > revision) {
+                let key = &event.kv.as_ref().unwrap().key;
+                match &event.prev_kv {
+                    Some(prev_kv) => kvs.insert(key.clone(), prev_kv.clone()),
+                    None => kvs.remove(key),
+                };
+            }
+        }
+        Ok(kvs)
+    }
+
+    fn range(&self, request: &RangeRequest) -> Result<RangeResponse, Status> {
+        let mut kvs = self
+            .kvs_at(request.revision)?
+            .into_values()
+            .filter(|kv| in_range(&kv.key, &request.key, &request.range_end))
+            .collect::<Vec<_>>();
+        if request.sort_order == SORT_DESCEND {
+            kvs.reverse();
+        }
+        let count = kvs.len() as i64;
+        let more = request.limit > 0 && count > request.limit;
+        if more {
+            kvs.truncate(request.limit as usize);
+        }
+        if request.keys_only {
+            kvs.iter_mut().for_each(|kv| kv.value.clear());
+        }
+        if request.count_only {
+            kvs.clear();
+        }
+        Ok(RangeResponse {
+            header: self.header(),
+            kvs,
+            more,
+            count,
+        })
+    }
+
+    fn check_lease(&self, lease: i64) -> Result<(), Status> {
+        if lease == 0 || self.leases.contains_key(&lease) {
+            Ok(())
+        } else {
+            Err(Status::not_found("etcdserver: requested lease not found"))
+        }
+    }
+
+    fn put(&mut self, request: PutRequest) -> PutResponse {
+        let revision = self.next_revision();
+        let prev_kv = self.kvs.get(&request.key).cloned();
+        let kv = KeyValue {
+            key: request.key.clone(),
+            create_revision: prev_kv.as_ref().map_or(revision, |kv| kv.create_revision),
+            mod_revision: revision,
+            version: prev_kv.as_ref().map_or(0, |kv| kv.version) + 1,
+            value: request.value,
+            lease: request.lease,
+        };
+        self.kvs.insert(request.key, kv.clone());
+        self.history.push((
+            revision,
+            Event {
+                r#type: EVENT_PUT,
+                kv: Some(kv),
+                prev_kv: prev_kv.clone(),
+            },
+        ));
+        PutResponse {
+            header: self.header(),
+            prev_kv: prev_kv.filter(|_| request.prev_kv),
+        }
+    }
+
+    fn delete_range(&mut self, request: DeleteRangeRequest) -> DeleteRangeResponse {
+        let revision = self.next_revision();
+        let keys = self
+            .kvs
+            .keys()
+            .filter(|key| in_range(key, &request.key, &request.range_end))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut prev_kvs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let prev_kv = self.kvs.remove(&key).unwrap();
+            self.history.push((
+                revision,
+                Event {
+                    r#type: EVENT_DELETE,
+                    kv: Some(KeyValue {
+                        key,
+                        mod_revision: revision,
+                        ..Default::default()
+                    }),
+                    prev_kv: Some(prev_kv.clone()),
+                },
+            ));
+            prev_kvs.push(prev_kv);
+        }
+        DeleteRangeResponse {
+            header: self.header(),
+            deleted: prev_kvs.len() as i64,
+            prev_kvs: if request.prev_kv {
+                prev_kvs
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// A comparison over a range holds if it holds for every key, or for a
+    /// zero key value if there are none, except for values.
+    fn compare(&self, compare: &Compare) -> bool {
+        let kvs = self
+            .kvs
+            .values()
+            .filter(|kv| in_range(&kv.key, &compare.key, &compare.range_end))
+            .collect::<Vec<_>>();
+        if kvs.is_empty() {
+            return compare.target != TARGET_VALUE && compare_kv(compare, &KeyValue::default());
+        }
+        kvs.into_iter().all(|kv| compare_kv(compare, kv))
+    }
+
+    fn txn(&mut self, request: TxnRequest) -> Result<TxnResponse, Status> {
+        let succeeded = request.compare.iter().all(|compare| self.compare(compare));
+        let ops = if succeeded {
+            request.success
+        } else {
+            request.failure
+        };
+        // nothing is written if a lease is missing
+        for op in &ops {
+            if let Some(Request::Put(put)) = &op.request {
+                self.check_lease(put.lease)?;
+            }
+        }
+        let mut responses = Vec::with_capacity(ops.len());
+        for op in ops {
+            let response = match op.request {
+                Some(Request::Range(range)) => Response::Range(self.range(&range)?),
+                Some(Request::Put(put)) => Response::Put(self.put(put)),
+                Some(Request::DeleteRange(delete)) => {
+                    Response::DeleteRange(self.delete_range(delete))
+                }
+                Some(Request::Txn(_)) | None => {
+                    return Err(Status::unimplemented("nested txn"));
+                }
+            };
+            responses.push(ResponseOp {
+                response: Some(response),
+            });
+        }
+        Ok(TxnResponse {
+            header: self.header(),
+            succeeded,
+            responses,
+        })
+    }
+
+    fn compact(&mut self, revision: i64) -> Result<(), Status> {
+        if revision > self.revision {
+            return Err(Status::out_of_range(
+                "etcdserver: mvcc: required revision is a future revision",
+            ));
+        }
+        if revision <= self.compacted {
+            return Err(Status::out_of_range(
+                "etcdserver: mvcc: required revision has been compacted",
+            ));
+        }
+        self.compacted = revision;
+        self.history.retain(|(r, _)| *r >= revision);
+        Ok(())
+    }
+
+    fn grant(&mut self, ttl: i64, id: i64) -> i64 {
+        let id = if id == 0 {
+            self.next_lease += 1;
+            self.next_lease
+        } else {
+            id
+        };
+        self.leases.insert(
+            id,
+            Lease {
+                ttl,
+                expires: Instant::now() + Duration::from_secs(ttl as u64),
+            },
+        );
+        id
+    }
+
+    /// Remaining ttl after renewing the lease, 0 if it is gone.
+    fn keep_alive(&mut self, id: i64) -> i64 {
+        match self.leases.get_mut(&id) {
+            Some(lease) if lease.expires > Instant::now() => {
+                lease.expires = Instant::now() + Duration::from_secs(lease.ttl as u64);
+                lease.ttl
+            }
+            _ => 0,
+        }
+    }
+
+    /// Remove the lease and delete the keys attached to it.
+    fn revoke(&mut self, id: i64) -> bool {
+        if self.leases.remove(&id).is_none() {
+            return false;
+        }
+        let keys = self
+            .kvs
+            .values()
+            .filter(|kv| kv.lease == id)
+            .map(|kv| kv.key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.delete_range(DeleteRangeRequest {
+                key,
+                ..Default::default()
+            });
+        }
+        true
+    }
+
+    fn revoke_expired(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.revoke(id);
+        }
+    }
+
+    /// The key of `prefix` created first, the owner of a lock or the leader.
+    fn oldest(&self, prefix: &[u8]) -> Option<&KeyValue> {
+        self.kvs
+            .values()
+            .filter(|kv| kv.key.starts_with(prefix))
+            .min_by_key(|kv| kv.create_revision)
+    }
+}
+
+fn compare_kv(compare: &Compare, kv: &KeyValue) -> bool {
+    let ordering = match &compare.target_union {
+        Some(TargetUnion::Version(version)) => kv.version.cmp(version),
+        Some(TargetUnion::CreateRevision(revision)) => kv.create_revision.cmp(revision),
+        Some(TargetUnion::ModRevision(revision)) => kv.mod_revision.cmp(revision),
+        Some(TargetUnion::Value(value)) => kv.value.cmp(value),
+        Some(TargetUnion::Lease(lease)) => kv.lease.cmp(lease),
+        None => return false,
+    };
+    match compare.result {
+        COMPARE_EQUAL => ordering.is_eq(),
+        COMPARE_GREATER => ordering.is_gt(),
+        COMPARE_LESS => ordering.is_lt(),
+        _ => ordering.is_ne(),
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct FakeEtcd {
+    store: Arc<Mutex<Store>>,
+    /// revision of the last write, for watches and waiting lock owners
+    changes: Arc<watch::Sender<i64>>,
+    /// bumped to break all watch streams
+    disconnects: Arc<watch::Sender<u64>>,
+}
+
+impl FakeEtcd {
+    /// Listen on a free local port.
+    pub(crate) async fn start() -> (Self, String) {
+        Self::serve(Server::builder()).await
+    }
+
+    /// Listen on a free local port with TLS, the url still starts with
+    /// `http://` like endpoints which [`Etcd::new`] switches to `https://`.
+    #[cfg(feature = "etcd-tls")]
+    pub(crate) async fn start_tls(cert_pem: &str, key_pem: &str) -> (Self, String) {
+        use tonic::transport::{Identity, ServerTlsConfig};
+
+        let tls = ServerTlsConfig::new().identity(Identity::from_pem(cert_pem, key_pem));
+        Self::serve(Server::builder().tls_config(tls).unwrap()).await
+    }
+
+    async fn serve(mut server: Server) -> (Self, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let fake = Self {
+            store: Default::default(),
+            changes: Arc::new(watch::channel(1).0),
+            disconnects: Arc::new(watch::channel(0).0),
+        };
+        let server = server
+            .add_service(KvService(fake.clone()))
+            .add_service(WatchService(fake.clone()))
+            .add_service(LeaseService(fake.clone()))
+            .add_service(LockService(fake.clone()))
+            .add_service(ElectionService(fake.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+        let reaper = fake.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                reaper
+                    .write(|store| {
+                        store.revoke_expired();
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        });
+        (fake, url)
+    }
+
+    /// An [`Etcd`] connected to a new fake server.
+    pub(crate) async fn etcd() -> (Self, Etcd) {
+        let (fake, url) = Self::start().await;
+        let config = EtcdConfig {
+            endpoints: vec![url],
+            ..Default::default()
+        };
+        (fake, Etcd::new(&config).await.unwrap())
+    }
+
+    /// Keys starting with `prefix` with their values, namespaces included.
+    pub(crate) fn kvs(&self, prefix: &str) -> Vec<(String, String)> {
+        self.store
+            .lock()
+            .unwrap()
+            .kvs
+            .values()
+            .filter(|kv| kv.key.starts_with(prefix.as_bytes()))
+            .map(|kv| {
+                (
+                    String::from_utf8_lossy(&kv.key).into_owned(),
+                    String::from_utf8_lossy(&kv.value).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn keys(&self, prefix: &str) -> Vec<String> {
+        self.kvs(prefix).into_iter().map(|(key, _)| key).collect()
+    }
+
+    pub(crate) fn revision(&self) -> i64 {
+        self.store.lock().unwrap().revision
+    }
+
+    /// Drop the history before `revision`.
+    pub(crate) fn compact(&self, revision: i64) {
+        self.store.lock().unwrap().compact(revision).unwrap();
+    }
+
+    /// Break all open watch streams, as a lost connection would.
+    pub(crate) fn disconnect_watches(&self) {
+        self.disconnects.send_modify(|generation| *generation += 1);
+    }
+
+    /// Expire the lease now, as if it was not renewed within its ttl.
+    pub(crate) fn expire_lease(&self, id: i64) {
+        self.write(|store| {
+            store.revoke(id);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Store) -> T) -> T {
+        f(&self.store.lock().unwrap())
+    }
+
+    /// Run a request which may write, all its writes share one revision.
+    fn write<T>(&self, f: impl FnOnce(&mut Store) -> Result<T, Status>) -> Result<T, Status> {
+        let mut store = self.store.lock().unwrap();
+        let result = f(&mut store);
+        if store.finish() {
+            self.changes.send_replace(store.revision);
+        }
+        result
+    }
+
+    /// Wait until `key` is the oldest key of `prefix`, fails once it is gone.
+    async fn wait_until_oldest(&self, prefix: &[u8], key: &[u8]) -> Result<KeyValue, Status> {
+        let mut changes = self.changes.subscribe();
+        loop {
+            changes.borrow_and_update();
+            {
+                let store = self.store.lock().unwrap();
+                let Some(kv) = store.kvs.get(key) else {
+                    return Err(Status::not_found("etcdserver: requested lease not found"));
+                };
+                if store.oldest(prefix).is_some_and(|oldest| oldest.key == key) {
+                    return Ok(kv.clone());
+                }
+            }
+            changes
+                .changed()
+                .await
+                .map_err(|_| Status::unavailable("shutting down"))?;
+        }
+    }
+
+    async fn range(self, request: RangeRequest) -> Result<RangeResponse, Status> {
+        self.read(|store| store.range(&request))
+    }
+
+    async fn put(self, request: PutRequest) -> Result<PutResponse, Status> {
+        self.write(|store| {
+            store.check_lease(request.lease)?;
+            Ok(store.put(request))
+        })
+    }
+
+    async fn delete_range(
+        self,
+        request: DeleteRangeRequest,
+    ) -> Result<DeleteRangeResponse, Status> {
+        self.write(|store| Ok(store.delete_range(request)))
+    }
+
+    async fn txn(self, request: TxnRequest) -> Result<TxnResponse, Status> {
+        self.write(|store| store.txn(request))
+    }
+
+    async fn compact_rpc(self, request: CompactionRequest) -> Result<CompactionResponse, Status> {
+        self.write(|store| {
+            store.compact(request.revision)?;
+            Ok(CompactionResponse {
+                header: store.header(),
+            })
+        })
+    }
+
+    fn watch(
+        self,
+        mut requests: Streaming<WatchRequest>,
+    ) -> mpsc::Receiver<Result<WatchResponse, Status>> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut disconnects = self.disconnects.subscribe();
+            let mut watchers = HashMap::new();
+            let mut next_id = 0;
+            loop {
+                tokio::select! {
+                    _ = disconnects.changed() => {
+                        let _ = tx.send(Err(Status::unavailable("watch stream broken"))).await;
+                        break;
+                    }
+                    request = requests.message() => match request {
+                        Ok(Some(WatchRequest {
+                            request_union: Some(WatchRequestUnion::Create(create)),
+                        })) => {
+                            let watcher = tokio::spawn(self.clone().watcher(next_id, create, tx.clone()));
+                            watchers.insert(next_id, watcher);
+                            next_id += 1;
+                        }
+                        Ok(Some(WatchRequest {
+                            request_union: Some(WatchRequestUnion::Cancel(cancel)),
+                        })) => {
+                            if let Some(watcher) = watchers.remove(&cancel.watch_id) {
+                                watcher.abort();
+                                let response = WatchResponse {
+                                    header: self.read(Store::header),
+                                    watch_id: cancel.watch_id,
+                                    canceled: true,
+                                    ..Default::default()
+                                };
+                                let _ = tx.send(Ok(response)).await;
+                            }
+                        }
+                        Ok(Some(_)) => {}
+                        _ => break,
+                    }
+                }
+            }
+            for watcher in watchers.into_values() {
+                watcher.abort();
+            }
+        });
+        rx
+    }
+
+    async fn watcher(
+        self,
+        id: i64,
+        create: WatchCreateRequest,
+        tx: mpsc::Sender<Result<WatchResponse, Status>>,
+    ) {
+        let mut changes = self.changes.subscribe();
+        let (header, mut next) = self.read(|store| {
+            let next = match create.start_revision {
+                0 => store.revision + 1,
+                revision => revision,
+            };
+            (store.header(), next)
+        });
+        let created = WatchResponse {
+            header,
+            watch_id: id,
+            created: true,
+            ..Default::default()
+        };
+        if tx.send(Ok(created)).await.is_err() {
+            return;
+        }
+        loop {
+            changes.borrow_and_update();
+            let response = self.read(|store| {
+                if next < store.compacted {
+                    return Some(WatchResponse {
+                        header: store.header(),
+                        watch_id: id,
+                        canceled: true,
+                        compact_revision: store.compacted,
+                        cancel_reason: "etcdserver: mvcc: required revision has been compacted"
+                            .to_owned(),
+                        ..Default::default()
+                    });
+                }
+                let events = store
+                    .history
+                    .iter()
+                    .filter(|(revision, event)| {
+                        *revision >= next
+                            && in_range(
+                                &event.kv.as_ref().unwrap().key,
+                                &create.key,
+                                &create.range_end,
+                            )
+                    })
+                    .map(|(_, event)| Event {
+                        prev_kv: event.prev_kv.clone().filter(|_| create.prev_kv),
+                        ..event.clone()
+                    })
+                    .collect::<Vec<_>>();
+                next = store.revision + 1;
+                (!events.is_empty()).then(|| WatchResponse {
+                    header: store.header(),
+                    watch_id: id,
+                    events,
+                    ..Default::default()
+                })
+            });
+            if let Some(response) = response {
+                let canceled = response.canceled;
+                if tx.send(Ok(response)).await.is_err() || canceled {
+                    return;
+                }
+            }
+            if changes.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn lease_grant(self, request: LeaseGrantRequest) -> Result<LeaseGrantResponse, Status> {
+        self.write(|store| {
+            let id = store.grant(request.ttl, request.id);
+            Ok(LeaseGrantResponse {
+                header: store.header(),
+                id,
+                ttl: request.ttl,
+            })
+        })
+    }
+
+    async fn lease_revoke(
+        self,
+        request: LeaseRevokeRequest,
+    ) -> Result<LeaseRevokeResponse, Status> {
+        self.write(|store| {
+            if !store.revoke(request.id) {
+                return Err(Status::not_found("etcdserver: requested lease not found"));
+            }
+            Ok(LeaseRevokeResponse {
+                header: store.header(),
+            })
+        })
+    }
+
+    fn lease_keep_alive(
+        self,
+        mut requests: Streaming<LeaseKeepAliveRequest>,
+    ) -> mpsc::Receiver<Result<LeaseKeepAliveResponse, Status>> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = requests.message().await {
+                let response = self
+                    .write(|store| {
+                        Ok(LeaseKeepAliveResponse {
+                            ttl: store.keep_alive(request.id),
+                            header: store.header(),
+                            id: request.id,
+                        })
+                    })
+                    .unwrap();
+                if tx.send(Ok(response)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Write the key `{name}/{lease}` unless it exists.
+    fn create_owner_key(&self, name: &[u8], lease: i64, value: Vec<u8>) -> Result<Vec<u8>, Status> {
+        let key = [name, format!("/{lease:x}").as_bytes()].concat();
+        self.write(|store| {
+            store.check_lease(lease)?;
+            if !store.kvs.contains_key(&key) {
+                store.put(PutRequest {
+                    key: key.clone(),
+                    value,
+                    lease,
+                    prev_kv: false,
+                });
+            }
+            Ok(())
+        })?;
+        Ok(key)
+    }
+
+    async fn lock(self, request: LockRequest) -> Result<LockResponse, Status> {
+        let key = self.create_owner_key(&request.name, request.lease, Vec::new())?;
+        self.wait_until_oldest(&[request.name.as_slice(), b"/"].concat(), &key)
+            .await?;
+        Ok(LockResponse {
+            header: self.read(Store::header),
+            key,
+        })
+    }
+
+    async fn unlock(self, request: UnlockRequest) -> Result<UnlockResponse, Status> {
+        self.write(|store| {
+            store.delete_range(DeleteRangeRequest {
+                key: request.key,
+                ..Default::default()
+            });
+            Ok(UnlockResponse {
+                header: store.header(),
+            })
+        })
+    }
+
+    async fn campaign(self, request: CampaignRequest) -> Result<CampaignResponse, Status> {
+        let key = self.create_owner_key(&request.name, request.lease, request.value)?;
+        let kv = self
+            .wait_until_oldest(&[request.name.as_slice(), b"/"].concat(), &key)
+            .await?;
+        Ok(CampaignResponse {
+            header: self.read(Store::header),
+            leader: Some(LeaderKey {
+                name: request.name,
+                key,
+                rev: kv.create_revision,
+                lease: kv.lease,
+            }),
+        })
+    }
+
+    /// Whether `leader` still owns its key.
+    fn is_leader(store: &Store, leader: &LeaderKey) -> bool {
+        store
+            .kvs
+            .get(&leader.key)
+            .is_some_and(|kv| kv.create_revision == leader.rev)
+    }
+
+    async fn proclaim(self, request: ProclaimRequest) -> Result<ProclaimResponse, Status> {
+        let leader = request.leader.unwrap_or_default();
+        self.write(|store| {
+            if !Self::is_leader(store, &leader) {
+                return Err(Status::failed_precondition("election: not leader"));
+            }
+            store.put(PutRequest {
+                key: leader.key,
+                value: request.value,
+                lease: leader.lease,
+                prev_kv: false,
+            });
+            Ok(ProclaimResponse {
+                header: store.header(),
+            })
+        })
+    }
+
+    async fn leader(self, request: LeaderRequest) -> Result<LeaderResponse, Status> {
+        let prefix = [request.name.as_slice(), b"/"].concat();
+        self.read(|store| match store.oldest(&prefix) {
+            Some(kv) => Ok(LeaderResponse {
+                header: store.header(),
+                kv: Some(kv.clone()),
+            }),
+            None => Err(Status::unknown("election: no leader")),
+        })
+    }
+
+    /// Send the leader whenever it or its value changes.
+    fn observe(self, request: LeaderRequest) -> mpsc::Receiver<Result<LeaderResponse, Status>> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let prefix = [request.name.as_slice(), b"/"].concat();
+            let mut changes = self.changes.subscribe();
+            let mut last = None;
+            loop {
+                changes.borrow_and_update();
+                let response = self.read(|store| {
+                    let kv = store.oldest(&prefix)?;
+                    let current = Some((kv.key.clone(), kv.mod_revision));
+                    (current != last).then(|| {
+                        last = current;
+                        LeaderResponse {
+                            header: store.header(),
+                            kv: Some(kv.clone()),
+                        }
+                    })
+                });
+                if let Some(response) = response {
+                    if tx.send(Ok(response)).await.is_err() {
+                        return;
+                    }
+                }
+                if changes.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+
+    async fn resign(self, request: ResignRequest) -> Result<ResignResponse, Status> {
+        let leader = request.leader.unwrap_or_default();
+        self.write(|store| {
+            if Self::is_leader(store, &leader) {
+                store.delete_range(DeleteRangeRequest {
+                    key: leader.key,
+                    ..Default::default()
+                });
+            }
+            Ok(ResignResponse {
+                header: store.header(),
+            })
+        })
+    }
+
+    async fn route(self, request: http::Request<BoxBody>) -> http::Response<BoxBody> {
+        let path = request.uri().path().to_owned();
+        let fake = self;
+        match path.as_str() {
+            "/etcdserverpb.KV/Range" => unary(request, move |r| fake.clone().range(r)).await,
+            "/etcdserverpb.KV/Put" => unary(request, move |r| fake.clone().put(r)).await,
+            "/etcdserverpb.KV/DeleteRange" => {
+                unary(request, move |r| fake.clone().delete_range(r)).await
+            }
+            "/etcdserverpb.KV/Txn" => unary(request, move |r| fake.clone().txn(r)).await,
+            "/etcdserverpb.KV/Compact" => {
+                unary(request, move |r| fake.clone().compact_rpc(r)).await
+            }
+            "/etcdserverpb.Watch/Watch" => streaming(request, move |r| fake.clone().watch(r)).await,
+            "/etcdserverpb.Lease/LeaseGrant" => {
+                unary(request, move |r| fake.clone().lease_grant(r)).await
+            }
+            "/etcdserverpb.Lease/LeaseRevoke" => {
+                unary(request, move |r| fake.clone().lease_revoke(r)).await
+            }
+            "/etcdserverpb.Lease/LeaseKeepAlive" => {
+                streaming(request, move |r| fake.clone().lease_keep_alive(r)).await
+            }
+            "/v3lockpb.Lock/Lock" => unary(request, move |r| fake.clone().lock(r)).await,
+            "/v3lockpb.Lock/Unlock" => unary(request, move |r| fake.clone().unlock(r)).await,
+            "/v3electionpb.Election/Campaign" => {
+                unary(request, move |r| fake.clone().campaign(r)).await
+            }
+            "/v3electionpb.Election/Proclaim" => {
+                unary(request, move |r| fake.clone().proclaim(r)).await
+            }
+            "/v3electionpb.Election/Leader" => {
+                unary(request, move |r| fake.clone().leader(r)).await
+            }
+            "/v3electionpb.Election/Observe" => {
+                server_streaming(request, move |r| fake.clone().observe(r)).await
+            }
+            "/v3electionpb.Election/Resign" => {
+                unary(request, move |r| fake.clone().resign(r)).await
+            }
+            _ => Status::unimplemented(path).into_http(),
+        }
+    }
+}
+
+struct Unary<F>(F);
+
+impl<Req, Resp, F, Fut> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Req) -> Fut,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = BoxFuture<tonic::Response<Resp>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let response = (self.0)(request.into_inner());
+        Box::pin(async move { response.await.map(tonic::Response::new) })
+    }
+}
+
+struct Streams<F>(F);
+
+impl<Req, Resp, F> StreamingService<Req> for Streams<F>
+where
+    F: FnMut(Streaming<Req>) -> mpsc::Receiver<Result<Resp, Status>>,
+{
+    type Response = Resp;
+    type ResponseStream = ReceiverStream<Result<Resp, Status>>;
+    type Future = std::future::Ready<Result<tonic::Response<Self::ResponseStream>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<Req>>) -> Self::Future {
+        let responses = (self.0)(request.into_inner());
+        std::future::ready(Ok(tonic::Response::new(ReceiverStream::new(responses))))
+    }
+}
+
+impl<Req, Resp, F> ServerStreamingService<Req> for Streams<F>
+where
+    F: FnMut(Req) -> mpsc::Receiver<Result<Resp, Status>>,
+{
+    type Response = Resp;
+    type ResponseStream = ReceiverStream<Result<Resp, Status>>;
+    type Future = std::future::Ready<Result<tonic::Response<Self::ResponseStream>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let responses = (self.0)(request.into_inner());
+        std::future::ready(Ok(tonic::Response::new(ReceiverStream::new(responses))))
+    }
+}
+
+async fn unary<Req, Resp, F, Fut>(
+    request: http::Request<BoxBody>,
+    handler: F,
+) -> http::Response<BoxBody>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Req) -> Fut,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+{
+    Grpc::new(ProstCodec::<Resp, Req>::default())
+        .unary(Unary(handler), request)
+        .await
+}
+
+async fn streaming<Req, Resp, F>(
+    request: http::Request<BoxBody>,
+    handler: F,
+) -> http::Response<BoxBody>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Streaming<Req>) -> mpsc::Receiver<Result<Resp, Status>> + Send,
+{
+    Grpc::new(ProstCodec::<Resp, Req>::default())
+        .streaming(Streams(handler), request)
+        .await
+}
+
+async fn server_streaming<Req, Resp, F>(
+    request: http::Request<BoxBody>,
+    handler: F,
+) -> http::Response<BoxBody>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Req) -> mpsc::Receiver<Result<Resp, Status>>,
+{
+    Grpc::new(ProstCodec::<Resp, Req>::default())
+        .server_streaming(Streams(handler), request)
+        .await
+}
+
+/// A gRPC service of the etcd API, all of them are routed by [`FakeEtcd::route`].
+macro_rules! service {
+    ($service:ident, $name:literal) => {
+        #[derive(Clone)]
+        struct $service(FakeEtcd);
+
+        impl NamedService for $service {
+            const NAME: &'static str = $name;
+        }
+
+        impl Service<http::Request<BoxBody>> for $service {
+            type Response = http::Response<BoxBody>;
+            type Error = Infallible;
+            type Future = BoxFuture<Self::Response, Infallible>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+                let fake = self.0.clone();
+                Box::pin(async move { Ok(fake.route(request).await) })
+            }
+        }
+    };
+}
+
+service!(KvService, "etcdserverpb.KV");
+service!(WatchService, "etcdserverpb.Watch");
+service!(LeaseService, "etcdserverpb.Lease");
+service!(LockService, "v3lockpb.Lock");
+service!(ElectionService, "v3electionpb.Election");
@@ -0,0 +1,146 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::LockOptions;
+use tracing::debug;
+
+use super::{session::EtcdSession, Etcd};
+use crate::metrics;
+
+/// A held etcd lock, released when dropped or once its session lease is lost.
+pub struct EtcdLock {
+    key: Vec<u8>,
+    session: EtcdSession,
+}
+
+impl EtcdLock {
+    /// Key owning the lock, exists as long as the lock is held and may guard
+    /// writes in a transaction.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Whether the session lease expired, e.g. after the process stalled longer than its ttl.
+    pub fn is_lost(&self) -> bool {
+        self.session.is_lost()
+    }
+
+    /// Wait until the lock is lost, e.g. to cancel the work it protects.
+    pub async fn lost(&self) {
+        self.session.lost().await
+    }
+}
+
+impl Etcd {
+    /// Acquire the lock `name`, waiting while it is held elsewhere.
+    ///
+    /// The lock is bound to a session lease of `ttl` seconds which is kept alive
+    /// in the background, it is released by [`Self::unlock`], by dropping the
+    /// returned [`EtcdLock`] or when the lease expires. Wrap the call in
+    /// `tokio::time::timeout` to bound the wait.
+    pub async fn lock(&self, name: impl Into<Vec<u8>>, ttl: i64) -> Result<EtcdLock> {
+        let mut client = self.client.clone();
+        let session = self.session(ttl).await?;
+        let response = metrics::observe(
+            "etcd",
+            "lock",
+            client.lock(
+                self.namespaced(name),
+                Some(LockOptions::new().with_lease(session.lease_id())),
+            ),
+        )
+        .await
+        .map_err(|e| eyre!("etcd lock failed: {e}"))?;
+        let key = self.strip_namespace(response.key());
+        debug!("etcd lock acquired: {}", String::from_utf8_lossy(&key));
+        Ok(EtcdLock { key, session })
+    }
+
+    pub async fn unlock(&self, lock: EtcdLock) -> Result<()> {
+        let mut client = self.client.clone();
+        metrics::observe("etcd", "unlock", client.unlock(self.namespaced(lock.key)))
+            .await
+            .map_err(|e| eyre!("etcd unlock failed: {e}"))?;
+        lock.session.revoke().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::etcd::fake::FakeEtcd;
+
+    #[tokio::test]
+    async fn lock_and_unlock() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let lock = etcd.lock("job", 10).await.unwrap();
+        assert!(lock.key().starts_with(b"job/"));
+        assert_eq!(
+            fake.keys("job/"),
+            [String::from_utf8_lossy(lock.key()).into_owned()]
+        );
+        assert!(!lock.is_lost());
+        etcd.unlock(lock).await.unwrap();
+        assert!(fake.keys("job/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn lock_waits_for_unlock() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        let lock = etcd.lock("job", 10).await.unwrap();
+        let waiting = tokio::spawn({
+            let etcd = etcd.clone();
+            async move { etcd.lock("job", 10).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        etcd.unlock(lock).await.unwrap();
+        let lock = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        etcd.unlock(lock).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock_released_when_dropped() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        drop(etcd.lock("job", 10).await.unwrap());
+        tokio::time::timeout(Duration::from_secs(1), etcd.lock("job", 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fake.keys("job/").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lock_lost_on_lease_expiry() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let lock = etcd.lock("job", 1).await.unwrap();
+        fake.expire_lease(lock.session.lease_id());
+        assert!(fake.keys("job/").is_empty());
+        tokio::time::timeout(Duration::from_secs(2), lock.lost())
+            .await
+            .unwrap();
+        assert!(lock.is_lost());
+        // another process takes the lock over
+        tokio::time::timeout(Duration::from_secs(1), etcd.lock("job", 10))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
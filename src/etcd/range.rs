@@ -0,0 +1,413 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{GetOptions, SortOrder, SortTarget};
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use super::{Etcd, KeyValue};
+use crate::metrics;
+
+const DEFAULT_PAGE_SIZE: i64 = 500;
+
+/// One page of a range read, see [`EtcdRange::next_page`].
+#[derive(Debug, Clone)]
+pub struct RangePage {
+    pub kvs: Vec<KeyValue>,
+    /// revision all pages of the range are read at
+    pub revision: i64,
+    /// whether more pages follow
+    pub more: bool,
+}
+
+/// Paginated read of all keys starting with a prefix, built with [`Etcd::range`].
+///
+/// All pages are read at the revision of the first one, so a walk sees a
+/// consistent snapshot even while keys change. The walk fails if that
+/// revision gets compacted before it is done.
+pub struct EtcdRange {
+    etcd: Etcd,
+    prefix: Vec<u8>,
+    start: Option<Vec<u8>>,
+    page_size: i64,
+    order: SortOrder,
+    revision: i64,
+    keys_only: bool,
+    /// key to continue from, the next key in ascending order, the exclusive
+    /// end in descending order
+    cursor: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl Etcd {
+    /// Read the keys starting with `prefix` page by page, in key order.
+    ///
+    /// Unlike [`Self::get_with_prefix`] this does not load the whole prefix
+    /// with a single request.
+    pub fn range(&self, prefix: impl Into<Vec<u8>>) -> EtcdRange {
+        EtcdRange {
+            etcd: self.clone(),
+            prefix: prefix.into(),
+            start: None,
+            page_size: DEFAULT_PAGE_SIZE,
+            order: SortOrder::Ascend,
+            revision: 0,
+            keys_only: false,
+            cursor: None,
+            done: false,
+        }
+    }
+
+    /// All keys starting with `prefix` without their values.
+    pub async fn keys_with_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let mut range = self.range(prefix).keys_only();
+        let mut keys = Vec::new();
+        while let Some(page) = range.next_page().await? {
+            keys.extend(page.kvs.iter().map(|kv| kv.key().to_vec()));
+        }
+        Ok(keys)
+    }
+
+    /// Number of keys starting with `prefix`.
+    pub async fn count_with_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<i64> {
+        self.range(prefix).count().await
+    }
+}
+
+impl EtcdRange {
+    /// Maximum number of keys read per request, defaults to 500.
+    pub fn page_size(mut self, page_size: i64) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Order by key, [`SortOrder::Descend`] makes the server sort the whole
+    /// range for every page.
+    pub const fn sort(mut self, order: SortOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Begin at `key` instead of the first key of the prefix, or the last one
+    /// in descending order, e.g. to resume an earlier walk. `key` should
+    /// start with the prefix.
+    pub fn start_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.start = Some(key.into());
+        self
+    }
+
+    /// Read at `revision` instead of the current one.
+    pub const fn revision(mut self, revision: i64) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Read keys without their values.
+    pub const fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self
+    }
+
+    const fn descending(&self) -> bool {
+        matches!(self.order, SortOrder::Descend)
+    }
+
+    /// First key and exclusive end of the keys left to read.
+    fn bounds(&self) -> (Vec<u8>, Vec<u8>) {
+        let end = prefix_end(&self.prefix);
+        if self.descending() {
+            let end = match (&self.cursor, &self.start) {
+                (Some(cursor), _) => cursor.clone(),
+                (None, Some(start)) => [start.as_slice(), &[0]].concat(),
+                (None, None) => end,
+            };
+            (self.prefix.clone(), end)
+        } else {
+            let key = self
+                .cursor
+                .clone()
+                .or_else(|| self.start.clone())
+                .unwrap_or_else(|| self.prefix.clone());
+            (key, end)
+        }
+    }
+
+    fn options(&self, end: Vec<u8>) -> GetOptions {
+        let mut options = GetOptions::new().with_range(end);
+        if self.revision > 0 {
+            options = options.with_revision(self.revision);
+        }
+        options
+    }
+
+    /// Read the next page, `None` once all keys were read.
+    pub async fn next_page(&mut self) -> Result<Option<RangePage>> {
+        if self.done {
+            return Ok(None);
+        }
+        let (key, end) = self.bounds();
+        let mut options = self
+            .options(end)
+            .with_limit(self.page_size)
+            .with_sort(SortTarget::Key, self.order);
+        if self.keys_only {
+            options = options.with_keys_only();
+        }
+        let mut response =
+            metrics::observe("etcd", "range", self.etcd.kv().get(key, Some(options)))
+                .await
+                .map_err(|e| eyre!("etcd range failed: {e}"))?;
+        if self.revision <= 0 {
+            self.revision = response
+                .header()
+                .map(|header| header.revision())
+                .unwrap_or_default();
+        }
+        let kvs = response.take_kvs();
+        match kvs.last() {
+            Some(last) if response.more() => {
+                self.cursor = Some(if self.descending() {
+                    last.key().to_vec()
+                } else {
+                    [last.key(), &[0]].concat()
+                });
+            }
+            _ => self.done = true,
+        }
+        Ok(Some(RangePage {
+            kvs,
+            revision: self.revision,
+            more: !self.done,
+        }))
+    }
+
+    /// Number of keys of the range, without reading them.
+    pub async fn count(&self) -> Result<i64> {
+        let (key, end) = self.bounds();
+        let options = self.options(end).with_count_only();
+        Ok(
+            metrics::observe("etcd", "count", self.etcd.kv().get(key, Some(options)))
+                .await
+                .map_err(|e| eyre!("etcd count failed: {e}"))?
+                .count(),
+        )
+    }
+
+    /// Walk the range key by key, reading the next page in the background.
+    ///
+    /// The stream ends after the last key or the first error, reading stops
+    /// once the stream is dropped.
+    pub fn into_stream(mut self) -> RangeStream {
+        let (kvs, rx) = mpsc::channel(self.page_size as usize);
+        tokio::spawn(async move {
+            loop {
+                let page = match self.next_page().await {
+                    Ok(Some(page)) => page,
+                    Ok(None) => return,
+                    Err(e) => {
+                        let _ = kvs.send(Err(e)).await;
+                        return;
+                    }
+                };
+                for kv in page.kvs {
+                    if kvs.send(Ok(kv)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        RangeStream { kvs: rx }
+    }
+}
+
+/// Keys of an [`EtcdRange`], see [`EtcdRange::into_stream`].
+pub struct RangeStream {
+    kvs: mpsc::Receiver<Result<KeyValue>>,
+}
+
+impl Stream for RangeStream {
+    type Item = Result<KeyValue>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.kvs.poll_recv(cx)
+    }
+}
+
+/// Smallest key after all keys starting with `prefix`, `\0` for all keys.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    async fn etcd_with(keys: &[&str]) -> (FakeEtcd, Etcd) {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        for key in keys {
+            etcd.put(*key, "value", 0).await.unwrap();
+        }
+        // neighbours of the prefix
+        etcd.put("svc", "value", 0).await.unwrap();
+        etcd.put("svc0", "value", 0).await.unwrap();
+        (fake, etcd)
+    }
+
+    async fn pages(mut range: EtcdRange) -> Vec<(Vec<String>, bool)> {
+        let mut pages = Vec::new();
+        while let Some(page) = range.next_page().await.unwrap() {
+            let keys = page
+                .kvs
+                .iter()
+                .map(|kv| kv.key_str().unwrap().to_owned())
+                .collect();
+            pages.push((keys, page.more));
+        }
+        pages
+    }
+
+    #[tokio::test]
+    async fn pages_in_key_order() {
+        let (_fake, etcd) = etcd_with(&["svc/c", "svc/a", "svc/e", "svc/b", "svc/d"]).await;
+        assert_eq!(
+            pages(etcd.range("svc/").page_size(2)).await,
+            [
+                (vec!["svc/a".to_owned(), "svc/b".to_owned()], true),
+                (vec!["svc/c".to_owned(), "svc/d".to_owned()], true),
+                (vec!["svc/e".to_owned()], false),
+            ]
+        );
+        assert_eq!(
+            pages(etcd.range("svc/").page_size(2).sort(SortOrder::Descend)).await,
+            [
+                (vec!["svc/e".to_owned(), "svc/d".to_owned()], true),
+                (vec!["svc/c".to_owned(), "svc/b".to_owned()], true),
+                (vec!["svc/a".to_owned()], false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn last_page_exactly_full() {
+        let (_fake, etcd) = etcd_with(&["svc/a", "svc/b", "svc/c", "svc/d"]).await;
+        // no empty page after the limit is reached
+        assert_eq!(
+            pages(etcd.range("svc/").page_size(2)).await,
+            [
+                (vec!["svc/a".to_owned(), "svc/b".to_owned()], true),
+                (vec!["svc/c".to_owned(), "svc/d".to_owned()], false),
+            ]
+        );
+        assert_eq!(
+            pages(etcd.range("svc/").page_size(4)).await,
+            [(
+                vec![
+                    "svc/a".to_owned(),
+                    "svc/b".to_owned(),
+                    "svc/c".to_owned(),
+                    "svc/d".to_owned()
+                ],
+                false
+            )]
+        );
+        assert_eq!(pages(etcd.range("none/")).await, [(vec![], false)]);
+    }
+
+    #[tokio::test]
+    async fn pages_read_at_first_revision() {
+        let (_fake, etcd) = etcd_with(&["svc/a", "svc/b", "svc/c"]).await;
+        let mut range = etcd.range("svc/").page_size(2);
+        let first = range.next_page().await.unwrap().unwrap();
+        etcd.put("svc/bb", "value", 0).await.unwrap();
+        etcd.delete("svc/c").await.unwrap();
+        let second = range.next_page().await.unwrap().unwrap();
+        assert_eq!(second.revision, first.revision);
+        assert_eq!(second.kvs.len(), 1);
+        assert_eq!(second.kvs[0].key(), b"svc/c");
+        assert!(range.next_page().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn compacted_revision_fails() {
+        let (fake, etcd) = etcd_with(&["svc/a", "svc/b", "svc/c"]).await;
+        let mut range = etcd.range("svc/").page_size(2);
+        range.next_page().await.unwrap().unwrap();
+        etcd.put("svc/d", "value", 0).await.unwrap();
+        fake.compact(fake.revision());
+        let e = range.next_page().await.unwrap_err();
+        assert!(e.to_string().contains("compacted"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn resume_from_start_key() {
+        let (_fake, etcd) = etcd_with(&["svc/a", "svc/b", "svc/c"]).await;
+        assert_eq!(
+            pages(etcd.range("svc/").start_key("svc/b").page_size(2)).await,
+            [(vec!["svc/b".to_owned(), "svc/c".to_owned()], false)]
+        );
+        assert_eq!(
+            pages(
+                etcd.range("svc/")
+                    .start_key("svc/b")
+                    .sort(SortOrder::Descend)
+            )
+            .await,
+            [(vec!["svc/b".to_owned(), "svc/a".to_owned()], false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_and_count() {
+        let (_fake, etcd) = etcd_with(&["svc/a", "svc/b", "svc/c"]).await;
+        assert_eq!(etcd.count_with_prefix("svc/").await.unwrap(), 3);
+        assert_eq!(etcd.count_with_prefix("none/").await.unwrap(), 0);
+        assert_eq!(
+            etcd.range("svc/").start_key("svc/b").count().await.unwrap(),
+            2
+        );
+        let keys = etcd.keys_with_prefix("svc/").await.unwrap();
+        assert_eq!(
+            keys,
+            [b"svc/a".to_vec(), b"svc/b".to_vec(), b"svc/c".to_vec()]
+        );
+        let mut stream = etcd.range("svc/").page_size(2).into_stream();
+        let mut streamed = Vec::new();
+        while let Some(kv) = stream.kvs.recv().await {
+            streamed.push(kv.unwrap().key().to_vec());
+        }
+        assert_eq!(streamed, keys);
+    }
+
+    #[test]
+    fn prefix_end_of_max_bytes() {
+        assert_eq!(prefix_end(b"svc/"), b"svc0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+        assert_eq!(prefix_end(b"\xff\xff"), [0]);
+        assert_eq!(prefix_end(b""), [0]);
+    }
+}
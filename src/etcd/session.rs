@@ -0,0 +1,262 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{Client, Error, LeaseKeepAliveStream, LeaseKeeper, PutOptions};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::warn;
+
+use super::{Etcd, KeyValue};
+use crate::metrics;
+
+/// A lease kept alive in the background for keys which should live as long
+/// as the process, revoked when dropped which deletes all attached keys.
+///
+/// The lease is lost if it could not be renewed within its ttl, e.g. during a
+/// network partition, the attached keys are gone then and a new session is
+/// needed to write them again.
+pub struct EtcdSession {
+    etcd: Etcd,
+    id: i64,
+    lost: watch::Receiver<bool>,
+    keep_alive: JoinHandle<()>,
+    revoked: bool,
+}
+
+impl Etcd {
+    /// Grant a lease of `ttl` seconds and keep it alive until the session is dropped.
+    pub async fn session(&self, ttl: i64) -> Result<EtcdSession> {
+        EtcdSession::grant(self, ttl).await
+    }
+}
+
+impl EtcdSession {
+    pub(crate) async fn grant(etcd: &Etcd, ttl: i64) -> Result<Self> {
+        let mut client = etcd.client.clone();
+        let id = metrics::observe("etcd", "lease_grant", client.lease_grant(ttl, None))
+            .await
+            .map_err(|e| eyre!("etcd lease_grant failed: {e}"))?
+            .id();
+        let (lost_tx, lost) = watch::channel(false);
+        let keep_alive = tokio::spawn(keep_alive(client, id, ttl, lost_tx));
+        Ok(Self {
+            etcd: etcd.clone(),
+            id,
+            lost,
+            keep_alive,
+            revoked: false,
+        })
+    }
+
+    pub const fn lease_id(&self) -> i64 {
+        self.id
+    }
+
+    /// Write `key` attached to the session lease, returns the previous key value.
+    pub async fn put(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<Option<KeyValue>> {
+        let option = PutOptions::new().with_lease(self.id).with_prev_key();
+        let put_rsp = metrics::observe("etcd", "put", self.etcd.kv().put(key, value, Some(option)))
+            .await
+            .map_err(|e| eyre!("etcd put failed: {e}"))?;
+        Ok(put_rsp.prev_key().cloned())
+    }
+
+    /// Whether the lease expired and the attached keys are gone.
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Wait until the lease is lost.
+    pub async fn lost(&self) {
+        let _ = self.lost.clone().wait_for(|lost| *lost).await;
+    }
+
+    /// Stop renewing and revoke the lease, deleting all attached keys.
+    pub async fn revoke(mut self) -> Result<()> {
+        self.keep_alive.abort();
+        self.revoked = true;
+        metrics::observe(
+            "etcd",
+            "lease_revoke",
+            self.etcd.client.lease_revoke(self.id),
+        )
+        .await
+        .map_err(|e| eyre!("etcd lease_revoke failed: {e}"))?;
+        Ok(())
+    }
+}
+
+impl Drop for EtcdSession {
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+        if self.revoked {
+            return;
+        }
+        let mut client = self.etcd.client.clone();
+        let id = self.id;
+        // otherwise the lease expires after its ttl
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(e) = client.lease_revoke(id).await {
+                    warn!("etcd lease_revoke failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Open a keep-alive stream, `None` if the lease expired.
+async fn open_keep_alive(
+    client: &mut Client,
+    id: i64,
+) -> Result<Option<(LeaseKeeper, LeaseKeepAliveStream)>> {
+    match metrics::observe("etcd", "lease_keep_alive", client.lease_keep_alive(id)).await {
+        Ok(stream) => Ok(Some(stream)),
+        // the client rejects the ttl 0 etcd answers for missing leases
+        Err(Error::LeaseKeepAliveError(_)) => Ok(None),
+        Err(e) => Err(eyre!("etcd lease_keep_alive failed: {e}")),
+    }
+}
+
+/// Renew the lease once, returns its remaining ttl which is 0 once it expired.
+pub(crate) async fn keep_alive_once(client: &mut Client, id: i64) -> Result<i64> {
+    let Some((mut keeper, mut responses)) = open_keep_alive(client, id).await? else {
+        return Ok(0);
+    };
+    keeper
+        .keep_alive()
+        .await
+        .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
+    let response = responses
+        .message()
+        .await
+        .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?
+        .ok_or_else(|| eyre!("etcd lease_keep_alive stream closed"))?;
+    Ok(response.ttl())
+}
+
+/// Renew the lease every `ttl / 3` seconds, re-opening the keep-alive stream
+/// after failures until the lease may have expired.
+async fn keep_alive(mut client: Client, id: i64, ttl: i64, lost: watch::Sender<bool>) {
+    let ttl = Duration::from_secs(ttl.max(1) as u64);
+    let mut expires = Instant::now() + ttl;
+    let mut stream = None;
+    loop {
+        let renewed_at = Instant::now();
+        let result = async {
+            let (keeper, responses) = match &mut stream {
+                Some(stream) => stream,
+                None => match open_keep_alive(&mut client, id).await? {
+                    Some(opened) => stream.insert(opened),
+                    None => return Ok(0),
+                },
+            };
+            keeper
+                .keep_alive()
+                .await
+                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
+            let response = responses
+                .message()
+                .await
+                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?
+                .ok_or_else(|| eyre!("etcd lease_keep_alive stream closed"))?;
+            Ok::<_, color_eyre::Report>(response.ttl())
+        }
+        .await;
+        match result {
+            Ok(remaining) if remaining > 0 => expires = renewed_at + ttl,
+            Ok(_) => {
+                warn!("etcd lease {id:x} expired");
+                lost.send_replace(true);
+                return;
+            }
+            Err(e) if Instant::now() < expires => {
+                warn!("{e}");
+                stream = None;
+            }
+            Err(e) => {
+                warn!("etcd lease {id:x} lost: {e}");
+                lost.send_replace(true);
+                return;
+            }
+        }
+        tokio::time::sleep(ttl / 3).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    #[tokio::test]
+    async fn keep_alive_outlives_ttl() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let session = etcd.session(1).await.unwrap();
+        session.put("key", "value").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(!session.is_lost());
+        assert_eq!(fake.keys("key"), ["key"]);
+    }
+
+    #[tokio::test]
+    async fn lost_on_lease_expiry() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let session = etcd.session(1).await.unwrap();
+        session.put("key", "value").await.unwrap();
+        fake.expire_lease(session.lease_id());
+        assert!(fake.keys("key").is_empty());
+        tokio::time::timeout(Duration::from_secs(2), session.lost())
+            .await
+            .unwrap();
+        assert!(session.is_lost());
+        assert!(session.put("key", "value").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn revoked_when_dropped() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let session = etcd.session(10).await.unwrap();
+        session.put("key", "value").await.unwrap();
+        drop(session);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(fake.keys("key").is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_once_expired() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let session = etcd.session(10).await.unwrap();
+        let mut client = etcd.client.clone();
+        assert_eq!(
+            keep_alive_once(&mut client, session.lease_id())
+                .await
+                .unwrap(),
+            10
+        );
+        fake.expire_lease(session.lease_id());
+        assert_eq!(
+            keep_alive_once(&mut client, session.lease_id())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{
+    Compare, CompareOp, DeleteOptions, GetOptions, KvClientPrefix, PutOptions, Txn, TxnOp,
+    TxnOpResponse,
+};
+
+use super::{Etcd, KeyValue};
+use crate::metrics;
+
+/// Result of one operation of a transaction.
+#[derive(Debug, Clone)]
+pub enum TxnOutput {
+    /// keys read
+    Get(Vec<KeyValue>),
+    /// previous key value, if the key existed
+    Put(Option<KeyValue>),
+    /// keys deleted
+    Delete(Vec<KeyValue>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TxnResult {
+    /// whether all comparisons held and the `then` branch ran
+    pub succeeded: bool,
+    /// outputs of the operations of the branch which ran, in order
+    pub outputs: Vec<TxnOutput>,
+}
+
+impl TxnResult {
+    /// All key values of [`Self::outputs`], in order.
+    pub fn kvs(&self) -> Vec<KeyValue> {
+        self.outputs
+            .iter()
+            .flat_map(|output| match output {
+                TxnOutput::Get(kvs) | TxnOutput::Delete(kvs) => kvs.clone(),
+                TxnOutput::Put(kv) => kv.iter().cloned().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Transaction built with [`Etcd::txn`], runs the `then` operations if all
+/// comparisons hold and the `else` operations otherwise.
+///
+/// A missing key has version, create and mod revision 0, comparing its value
+/// always fails.
+pub struct EtcdTxn {
+    kv: KvClientPrefix,
+    compares: Vec<Compare>,
+    then: Vec<TxnOp>,
+    otherwise: Vec<TxnOp>,
+}
+
+impl Etcd {
+    pub fn txn(&self) -> EtcdTxn {
+        EtcdTxn {
+            kv: self.kv(),
+            compares: Vec::new(),
+            then: Vec::new(),
+            otherwise: Vec::new(),
+        }
+    }
+
+    /// Replace the value of `key` if it equals `expected`, otherwise read the
+    /// current value.
+    pub async fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<TxnResult> {
+        let key = key.into();
+        self.txn()
+            .when_value(key.clone(), CompareOp::Equal, expected)
+            .then_put(key.clone(), value)
+            .else_get(key)
+            .commit()
+            .await
+    }
+
+    /// Write `key` if it does not exist, otherwise read the current value.
+    pub async fn create_if_absent(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<TxnResult> {
+        let key = key.into();
+        self.txn()
+            .when_create_revision(key.clone(), CompareOp::Equal, 0)
+            .then_put(key.clone(), value)
+            .else_get(key)
+            .commit()
+            .await
+    }
+
+    /// Delete `key` if its value equals `expected`, otherwise read the current value.
+    pub async fn delete_if_value(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+    ) -> Result<TxnResult> {
+        let key = key.into();
+        self.txn()
+            .when_value(key.clone(), CompareOp::Equal, expected)
+            .then_delete(key.clone())
+            .else_get(key)
+            .commit()
+            .await
+    }
+}
+
+impl EtcdTxn {
+    /// Add a comparison built directly, e.g. one over all keys of a prefix
+    /// which holds if it holds for every key, or for a missing key if there
+    /// are none.
+    pub fn when(mut self, compare: Compare) -> Self {
+        self.compares.push(compare);
+        self
+    }
+
+    pub fn when_value(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        op: CompareOp,
+        value: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.compares.push(Compare::value(key, op, value));
+        self
+    }
+
+    /// Compare the number of writes since the key was created.
+    pub fn when_version(mut self, key: impl Into<Vec<u8>>, op: CompareOp, version: i64) -> Self {
+        self.compares.push(Compare::version(key, op, version));
+        self
+    }
+
+    /// Compare the revision of the last write of the key.
+    pub fn when_mod_revision(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        op: CompareOp,
+        revision: i64,
+    ) -> Self {
+        self.compares.push(Compare::mod_revision(key, op, revision));
+        self
+    }
+
+    /// Compare the revision the key was created at.
+    pub fn when_create_revision(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        op: CompareOp,
+        revision: i64,
+    ) -> Self {
+        self.compares
+            .push(Compare::create_revision(key, op, revision));
+        self
+    }
+
+    pub fn then_get(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.then.push(TxnOp::get(key, None));
+        self
+    }
+
+    pub fn then_get_with_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.then
+            .push(TxnOp::get(prefix, Some(GetOptions::new().with_prefix())));
+        self
+    }
+
+    pub fn then_put(mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        self.then.push(put(key, value, 0));
+        self
+    }
+
+    /// Put the key attached to `lease`, e.g. [`super::EtcdSession::lease_id`].
+    pub fn then_put_with_lease(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        lease: i64,
+    ) -> Self {
+        self.then.push(put(key, value, lease));
+        self
+    }
+
+    pub fn then_delete(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.then.push(delete(key));
+        self
+    }
+
+    pub fn else_get(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.otherwise.push(TxnOp::get(key, None));
+        self
+    }
+
+    pub fn else_get_with_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.otherwise
+            .push(TxnOp::get(prefix, Some(GetOptions::new().with_prefix())));
+        self
+    }
+
+    pub fn else_put(mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        self.otherwise.push(put(key, value, 0));
+        self
+    }
+
+    pub fn else_put_with_lease(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        lease: i64,
+    ) -> Self {
+        self.otherwise.push(put(key, value, lease));
+        self
+    }
+
+    pub fn else_delete(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.otherwise.push(delete(key));
+        self
+    }
+
+    pub async fn commit(mut self) -> Result<TxnResult> {
+        let txn = Txn::new()
+            .when(self.compares)
+            .and_then(self.then)
+            .or_else(self.otherwise);
+        let response = metrics::observe("etcd", "txn", self.kv.txn(txn))
+            .await
+            .map_err(|e| eyre!("etcd txn failed: {e}"))?;
+        let outputs = response
+            .op_responses()
+            .into_iter()
+            .filter_map(|output| match output {
+                TxnOpResponse::Get(mut get) => Some(TxnOutput::Get(get.take_kvs())),
+                TxnOpResponse::Put(mut put) => Some(TxnOutput::Put(put.take_prev_key())),
+                TxnOpResponse::Delete(mut delete) => {
+                    Some(TxnOutput::Delete(delete.take_prev_kvs()))
+                }
+                // nested transactions are not built
+                TxnOpResponse::Txn(_) => None,
+            })
+            .collect();
+        Ok(TxnResult {
+            succeeded: response.succeeded(),
+            outputs,
+        })
+    }
+}
+
+fn put(key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, lease: i64) -> TxnOp {
+    let mut options = PutOptions::new().with_prev_key();
+    if lease != 0 {
+        options = options.with_lease(lease);
+    }
+    TxnOp::put(key, value, Some(options))
+}
+
+fn delete(key: impl Into<Vec<u8>>) -> TxnOp {
+    TxnOp::delete(key, Some(DeleteOptions::new().with_prev_key()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    fn value(result: &TxnResult) -> Vec<u8> {
+        result.kvs()[0].value().to_vec()
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("key", "1", 0).await.unwrap();
+        let swapped = etcd.compare_and_swap("key", "1", "2").await.unwrap();
+        assert!(swapped.succeeded);
+        // the previous value
+        assert_eq!(value(&swapped), b"1");
+        let failed = etcd.compare_and_swap("key", "1", "3").await.unwrap();
+        assert!(!failed.succeeded);
+        // the current value
+        assert_eq!(value(&failed), b"2");
+        assert_eq!(etcd.get("key").await.unwrap().value(), b"2");
+    }
+
+    #[tokio::test]
+    async fn compare_value_of_missing_key_fails() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let result = etcd.compare_and_swap("key", "", "1").await.unwrap();
+        assert!(!result.succeeded);
+        assert!(result.kvs().is_empty());
+        assert!(fake.keys("key").is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_if_absent() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        assert!(etcd.create_if_absent("key", "1").await.unwrap().succeeded);
+        let result = etcd.create_if_absent("key", "2").await.unwrap();
+        assert!(!result.succeeded);
+        assert_eq!(value(&result), b"1");
+    }
+
+    #[tokio::test]
+    async fn delete_if_value() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("key", "1", 0).await.unwrap();
+        assert!(!etcd.delete_if_value("key", "2").await.unwrap().succeeded);
+        let result = etcd.delete_if_value("key", "1").await.unwrap();
+        assert!(result.succeeded);
+        assert_eq!(value(&result), b"1");
+        assert!(fake.keys("key").is_empty());
+    }
+
+    #[tokio::test]
+    async fn guard_with_mod_revision() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("key", "1", 0).await.unwrap();
+        let read = etcd.get("key").await.unwrap();
+        let txn = || {
+            etcd.txn()
+                .when_mod_revision("key", CompareOp::Equal, read.mod_revision())
+                .then_put("key", "2")
+                .then_put("other", "2")
+                .then_get("key")
+                .else_get("key")
+        };
+        let result = txn().commit().await.unwrap();
+        assert!(result.succeeded);
+        assert!(matches!(
+            &result.outputs[..],
+            [TxnOutput::Put(Some(_)), TxnOutput::Put(None), TxnOutput::Get(kvs)]
+                if kvs[0].value() == b"2"
+        ));
+        // the key changed since it was read
+        let result = txn().commit().await.unwrap();
+        assert!(!result.succeeded);
+        assert_eq!(result.outputs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn compare_over_prefix() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        let txn = || {
+            etcd.txn()
+                .when(Compare::version("svc/", CompareOp::Equal, 0).with_prefix())
+                .then_put("svc/a", "1")
+                .else_get_with_prefix("svc/")
+        };
+        // holds for an empty prefix
+        assert!(txn().commit().await.unwrap().succeeded);
+        let result = txn().commit().await.unwrap();
+        assert!(!result.succeeded);
+        assert_eq!(value(&result), b"1");
+    }
+
+    #[tokio::test]
+    async fn missing_lease_writes_nothing() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let result = etcd
+            .txn()
+            .then_put("a", "1")
+            .then_put_with_lease("b", "1", 0x7fff)
+            .commit()
+            .await;
+        assert!(result.is_err());
+        assert!(fake.keys("").is_empty());
+    }
+}
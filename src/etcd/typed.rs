@@ -0,0 +1,154 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::GetOptions;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "bincode")]
+use crate::codec::Bincode;
+#[cfg(feature = "msgpack")]
+use crate::codec::MsgPack;
+use crate::{
+    codec::{Codec, Json},
+    metrics,
+    repository::RepositoryStore,
+};
+
+use super::Etcd;
+
+impl Etcd {
+    /// Read `key` decoded with `C`, `None` if it does not exist.
+    pub async fn get_as<C: Codec, T: DeserializeOwned>(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<Option<T>> {
+        let mut kv = self.kv();
+        metrics::observe(
+            "etcd",
+            "get",
+            kv.get(key, Some(GetOptions::new().with_limit(1))),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .first()
+        .map(|kv| C::decode(kv.value()))
+        .transpose()
+    }
+
+    /// Write `value` encoded with `C`, like [`Self::put`].
+    pub async fn put_as<C: Codec, T: Serialize>(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: &T,
+        ttl: i64,
+    ) -> Result<()> {
+        self.put(key, C::encode(value)?, ttl).await?;
+        Ok(())
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<Option<T>> {
+        self.get_as::<Json, T>(key).await
+    }
+
+    pub async fn put_json<T: Serialize>(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: &T,
+        ttl: i64,
+    ) -> Result<()> {
+        self.put_as::<Json, T>(key, value, ttl).await
+    }
+
+    #[cfg(feature = "bincode")]
+    pub async fn get_bincode<T: DeserializeOwned>(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<Option<T>> {
+        self.get_as::<Bincode, T>(key).await
+    }
+
+    #[cfg(feature = "bincode")]
+    pub async fn put_bincode<T: Serialize>(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: &T,
+        ttl: i64,
+    ) -> Result<()> {
+        self.put_as::<Bincode, T>(key, value, ttl).await
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub async fn get_msgpack<T: DeserializeOwned>(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<Option<T>> {
+        self.get_as::<MsgPack, T>(key).await
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub async fn put_msgpack<T: Serialize>(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: &T,
+        ttl: i64,
+    ) -> Result<()> {
+        self.put_as::<MsgPack, T>(key, value, ttl).await
+    }
+}
+
+impl RepositoryStore for Etcd {
+    async fn entry(&self, namespace: &str, id: &str) -> Result<Option<Vec<u8>>> {
+        let mut kv = self.kv();
+        Ok(metrics::observe(
+            "etcd",
+            "get",
+            kv.get(
+                format!("{namespace}/{id}"),
+                Some(GetOptions::new().with_limit(1)),
+            ),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?
+        .kvs()
+        .first()
+        .map(|kv| kv.value().to_vec()))
+    }
+
+    async fn put_entry(&self, namespace: &str, id: &str, value: Vec<u8>) -> Result<()> {
+        self.put(format!("{namespace}/{id}"), value, 0).await?;
+        Ok(())
+    }
+
+    async fn delete_entry(&self, namespace: &str, id: &str) -> Result<bool> {
+        Ok(self.delete(format!("{namespace}/{id}")).await? > 0)
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let prefix = format!("{namespace}/");
+        Ok(self
+            .get_with_prefix(prefix.clone())
+            .await?
+            .into_iter()
+            .filter_map(|kv| {
+                let id = kv.key_str().ok()?.strip_prefix(&prefix)?.to_owned();
+                Some((id, kv.value().to_vec()))
+            })
+            .collect())
+    }
+}
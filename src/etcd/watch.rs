@@ -0,0 +1,342 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{EventType, GetOptions, WatchOptions};
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::{Etcd, KeyValue};
+use crate::metrics;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// revision of the write
+        revision: i64,
+    },
+    Delete {
+        key: Vec<u8>,
+        /// revision of the deletion
+        revision: i64,
+    },
+}
+
+/// Changes of the watched keys, see [`Etcd::watch`].
+///
+/// Watching stops once the stream is dropped.
+pub struct WatchStream {
+    events: mpsc::Receiver<WatchEvent>,
+}
+
+impl Stream for WatchStream {
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Etcd {
+    /// Watch changes of `key` from now on.
+    ///
+    /// The watch is re-established after disconnects and resumes after the last
+    /// seen revision. If that revision was compacted in the meantime, the key
+    /// is read again and the differences are reported instead.
+    pub async fn watch(&self, key: impl Into<Vec<u8>>) -> Result<WatchStream> {
+        Watch::start(self.clone(), key.into(), false).await
+    }
+
+    /// Watch changes of all keys starting with `prefix`, like [`Self::watch`].
+    pub async fn watch_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<WatchStream> {
+        Watch::start(self.clone(), prefix.into(), true).await
+    }
+}
+
+struct Watch {
+    etcd: Etcd,
+    /// key without the namespace
+    key: Vec<u8>,
+    prefix: bool,
+    /// all events up to this revision were sent
+    revision: i64,
+    /// keys existing at `revision`, to find deletions missed during compaction
+    keys: HashSet<Vec<u8>>,
+    events: mpsc::Sender<WatchEvent>,
+}
+
+impl Watch {
+    async fn start(etcd: Etcd, key: Vec<u8>, prefix: bool) -> Result<WatchStream> {
+        let (events, rx) = mpsc::channel(64);
+        let mut watch = Self {
+            etcd,
+            key,
+            prefix,
+            revision: 0,
+            keys: HashSet::new(),
+            events,
+        };
+        let (revision, kvs) = watch.list(true).await?;
+        watch.revision = revision;
+        watch.keys = kvs.iter().map(|kv| kv.key().to_vec()).collect();
+        tokio::spawn(async move {
+            let events = watch.events.clone();
+            tokio::select! {
+                _ = events.closed() => {}
+                _ = watch.run() => {}
+            }
+        });
+        Ok(WatchStream { events: rx })
+    }
+
+    async fn list(&mut self, keys_only: bool) -> Result<(i64, Vec<KeyValue>)> {
+        let mut options = GetOptions::new();
+        if self.prefix {
+            options = options.with_prefix();
+        }
+        if keys_only {
+            options = options.with_keys_only();
+        }
+        let mut response = metrics::observe(
+            "etcd",
+            "get_prefix",
+            self.etcd.kv().get(self.key.clone(), Some(options)),
+        )
+        .await
+        .map_err(|e| eyre!("etcd get failed: {e}"))?;
+        let revision = response
+            .take_header()
+            .map(|header| header.revision())
+            .unwrap_or_default();
+        Ok((revision, response.take_kvs()))
+    }
+
+    /// Report changes between `revision` and the current state.
+    async fn relist(&mut self) -> Result<()> {
+        let (revision, kvs) = self.list(false).await?;
+        let mut keys = HashSet::with_capacity(kvs.len());
+        for kv in kvs {
+            keys.insert(kv.key().to_vec());
+            if kv.mod_revision() > self.revision {
+                self.send(WatchEvent::Put {
+                    key: kv.key().to_vec(),
+                    value: kv.value().to_vec(),
+                    revision: kv.mod_revision(),
+                })
+                .await?;
+            }
+        }
+        for key in std::mem::take(&mut self.keys) {
+            if !keys.contains(&key) {
+                self.send(WatchEvent::Delete { key, revision }).await?;
+            }
+        }
+        self.keys = keys;
+        self.revision = revision;
+        Ok(())
+    }
+
+    async fn send(&self, event: WatchEvent) -> Result<()> {
+        self.events
+            .send(event)
+            .await
+            .map_err(|_| eyre!("etcd watch stream dropped"))
+    }
+
+    async fn run(&mut self) {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            let mut established = false;
+            if let Err(e) = self.watch(&mut established).await {
+                warn!("{e}");
+            }
+            if established {
+                delay = Duration::from_secs(1);
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Watch until the stream fails, `established` tells whether it got that far.
+    async fn watch(&mut self, established: &mut bool) -> Result<()> {
+        let mut options = WatchOptions::new().with_start_revision(self.revision + 1);
+        if self.prefix {
+            options = options.with_prefix();
+        }
+        let (_watcher, mut stream) = self
+            .etcd
+            .client
+            .clone()
+            .watch(self.etcd.namespaced(self.key.clone()), Some(options))
+            .await
+            .map_err(|e| eyre!("etcd watch failed: {e}"))?;
+        loop {
+            let Some(response) = stream
+                .message()
+                .await
+                .map_err(|e| eyre!("etcd watch failed: {e}"))?
+            else {
+                return Err(eyre!("etcd watch stream closed"));
+            };
+            if response.compact_revision() > 0 {
+                info!(
+                    "etcd watch revision {} compacted, reading keys again",
+                    self.revision + 1
+                );
+                self.relist().await?;
+                *established = true;
+                return Ok(());
+            }
+            if response.canceled() {
+                return Err(eyre!("etcd watch canceled: {}", response.cancel_reason()));
+            }
+            *established |= response.created();
+            for event in response.events() {
+                let Some(kv) = event.kv() else {
+                    continue;
+                };
+                let key = self.etcd.strip_namespace(kv.key());
+                let event = match event.event_type() {
+                    EventType::Put => {
+                        self.keys.insert(key.clone());
+                        WatchEvent::Put {
+                            key,
+                            value: kv.value().to_vec(),
+                            revision: kv.mod_revision(),
+                        }
+                    }
+                    EventType::Delete => {
+                        self.keys.remove(&key);
+                        WatchEvent::Delete {
+                            key,
+                            revision: kv.mod_revision(),
+                        }
+                    }
+                };
+                self.revision = self.revision.max(kv.mod_revision());
+                self.send(event).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::fake::FakeEtcd;
+
+    /// Longer than the delay before re-watching.
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    async fn next(stream: &mut WatchStream) -> WatchEvent {
+        tokio::time::timeout(TIMEOUT, stream.events.recv())
+            .await
+            .expect("no watch event")
+            .unwrap()
+    }
+
+    async fn assert_no_event(stream: &mut WatchStream) {
+        let event = tokio::time::timeout(Duration::from_millis(200), stream.events.recv()).await;
+        assert!(event.is_err(), "unexpected {event:?}");
+    }
+
+    fn put(event: &WatchEvent) -> (&[u8], &[u8]) {
+        match event {
+            WatchEvent::Put { key, value, .. } => (key, value),
+            WatchEvent::Delete { .. } => panic!("expected a put, got {event:?}"),
+        }
+    }
+
+    fn delete(event: &WatchEvent) -> &[u8] {
+        match event {
+            WatchEvent::Delete { key, .. } => key,
+            WatchEvent::Put { .. } => panic!("expected a delete, got {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_key() {
+        let (_fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("other", "x", 0).await.unwrap();
+        let mut stream = etcd.watch("key").await.unwrap();
+        etcd.put("key", "1", 0).await.unwrap();
+        etcd.put("keys", "x", 0).await.unwrap();
+        etcd.delete("key").await.unwrap();
+        assert_eq!(put(&next(&mut stream).await), (&b"key"[..], &b"1"[..]));
+        assert_eq!(delete(&next(&mut stream).await), b"key");
+        assert_no_event(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn resume_from_revision_after_disconnect() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        let mut stream = etcd.watch_prefix("svc/").await.unwrap();
+        etcd.put("svc/a", "1", 0).await.unwrap();
+        assert_eq!(put(&next(&mut stream).await), (&b"svc/a"[..], &b"1"[..]));
+        fake.disconnect_watches();
+        // written while the watch is re-established
+        etcd.put("svc/b", "1", 0).await.unwrap();
+        etcd.delete("svc/a").await.unwrap();
+        assert_eq!(put(&next(&mut stream).await), (&b"svc/b"[..], &b"1"[..]));
+        assert_eq!(delete(&next(&mut stream).await), b"svc/a");
+        assert_no_event(&mut stream).await;
+        etcd.put("svc/c", "1", 0).await.unwrap();
+        assert_eq!(put(&next(&mut stream).await), (&b"svc/c"[..], &b"1"[..]));
+    }
+
+    #[tokio::test]
+    async fn relist_after_compaction() {
+        let (fake, etcd) = FakeEtcd::etcd().await;
+        etcd.put("svc/gone", "1", 0).await.unwrap();
+        etcd.put("svc/same", "1", 0).await.unwrap();
+        let mut stream = etcd.watch_prefix("svc/").await.unwrap();
+        etcd.put("svc/changed", "1", 0).await.unwrap();
+        assert_eq!(
+            put(&next(&mut stream).await),
+            (&b"svc/changed"[..], &b"1"[..])
+        );
+        fake.disconnect_watches();
+        etcd.delete("svc/gone").await.unwrap();
+        etcd.put("svc/changed", "2", 0).await.unwrap();
+        etcd.put("svc/new", "1", 0).await.unwrap();
+        etcd.put("svc/changed", "3", 0).await.unwrap();
+        etcd.put("other", "1", 0).await.unwrap();
+        fake.compact(fake.revision());
+        // the differences to the keys seen before instead of the history
+        assert_eq!(
+            put(&next(&mut stream).await),
+            (&b"svc/changed"[..], &b"3"[..])
+        );
+        assert_eq!(put(&next(&mut stream).await), (&b"svc/new"[..], &b"1"[..]));
+        assert_eq!(delete(&next(&mut stream).await), b"svc/gone");
+        assert_no_event(&mut stream).await;
+        // watching resumes after the relisted revision
+        etcd.put("svc/same", "2", 0).await.unwrap();
+        assert_eq!(put(&next(&mut stream).await), (&b"svc/same"[..], &b"2"[..]));
+        assert_no_event(&mut stream).await;
+    }
+}
@@ -12,6 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(any(
+    feature = "etcd",
+    feature = "redis",
+    feature = "bincode",
+    feature = "msgpack"
+))]
+pub mod codec;
+
 #[cfg(feature = "config")]
 pub mod configure;
 
@@ -21,6 +29,26 @@ pub mod etcd;
 #[cfg(feature = "log")]
 pub mod log;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+/// No-op instrumentation when the `metrics` feature is disabled.
+#[cfg(not(feature = "metrics"))]
+#[allow(dead_code)]
+mod metrics {
+    pub(crate) async fn observe<T, E>(
+        _backend: &str,
+        _operation: &str,
+        future: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        future.await
+    }
+
+    pub(crate) const fn reconnect(_backend: &str) {}
+
+    pub(crate) const fn heartbeat(_backend: &str, _ok: bool) {}
+}
+
 #[cfg(feature = "restful")]
 pub mod restful;
 
@@ -32,4 +60,14 @@ pub mod sm;
 
 pub mod error;
 
+#[cfg(any(feature = "etcd", feature = "redis"))]
+pub mod repository;
+
+#[cfg(any(feature = "etcd", feature = "redis"))]
+pub mod service_discovery;
+
 pub mod service_register;
+
+// dev-dependencies used by the tests of some features only
+#[cfg(test)]
+use {mlua as _, prost as _, rcgen as _, sha1_smol as _, tempfile as _, tokio as _, tonic as _};
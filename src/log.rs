@@ -12,19 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use chrono::{Local, Offset};
-use color_eyre::Result;
+mod format;
+#[cfg(target_os = "linux")]
+mod journald;
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "otel")]
+mod otel;
+mod rate_limit;
+mod redact;
+mod rolling;
+mod sink;
+mod syslog;
+mod time;
+
+use color_eyre::{eyre::eyre, Result};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use time::{format_description::well_known, UtcOffset};
-use tracing_subscriber::{fmt::format, fmt::time::OffsetTime, prelude::*, EnvFilter};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload, EnvFilter, Registry};
+
+pub use format::{JsonFields, JsonFormat, LogFormat};
+#[cfg(target_os = "linux")]
+pub use journald::JournaldWriter;
+#[cfg(all(test, feature = "otel", feature = "restful"))]
+pub(crate) use otel::InMemoryExporter;
+#[cfg(feature = "otel")]
+pub use otel::{extract_trace_context, inject_trace_context, OtelConfig};
+pub use rate_limit::{RateLimitConfig, RateLimitLayer};
+pub use redact::{RedactConfig, RedactEvent, RedactFields, Redactor};
+pub use rolling::{RollingFileWriter, RotationConfig, RotationPolicy};
+pub use sink::{NonBlockingConfig, SinkConfig, SinkKind};
+pub use syslog::{Facility, SyslogConfig, SyslogWriter};
+pub use time::{LogTimer, LogTimezone, TimestampFormat};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct LogConfig {
     max_level: String,
     filter: String,
+    format: LogFormat,
+    /// `utc`, `local`, a fixed offset like `+08:00` or an IANA name like `Asia/Shanghai`
+    timezone: String,
+    /// `rfc3339`, `rfc2822` or a custom `strftime` format
+    timestamp_format: String,
     rolling_file_path: Option<String>,
+    rotation: RotationConfig,
+    /// when empty, logs go to `rolling_file_path` if set, otherwise to stdout limited by `max_level`
+    sinks: Vec<SinkConfig>,
+    non_blocking: NonBlockingConfig,
+    redact: RedactConfig,
+    /// limit repeats of identical events
+    rate_limit: Option<RateLimitConfig>,
+    /// export spans to an OpenTelemetry collector
+    #[cfg(feature = "otel")]
+    otel: Option<OtelConfig>,
 }
 
 impl Default for LogConfig {
@@ -32,55 +76,283 @@ impl Default for LogConfig {
         Self {
             max_level: "info".to_owned(),
             filter: "info".to_owned(),
+            format: Default::default(),
+            timezone: "local".to_owned(),
+            timestamp_format: "rfc3339".to_owned(),
             rolling_file_path: Default::default(),
+            rotation: Default::default(),
+            sinks: Default::default(),
+            non_blocking: Default::default(),
+            redact: Default::default(),
+            rate_limit: Default::default(),
+            #[cfg(feature = "otel")]
+            otel: Default::default(),
+        }
+    }
+}
+
+impl LogConfig {
+    fn sinks(&self) -> Result<Vec<SinkConfig>> {
+        Ok(if !self.sinks.is_empty() {
+            self.sinks.clone()
+        } else if let Some(rolling_file_path) = &self.rolling_file_path {
+            vec![SinkConfig::file(rolling_file_path, &self.rotation)]
+        } else {
+            LevelFilter::from_str(&self.max_level)
+                .map_err(|e| eyre!("invalid log max_level `{}`: {e}", self.max_level))?;
+            vec![SinkConfig::stdout(&self.max_level)]
+        })
+    }
+
+    fn timer(&self) -> Result<LogTimer> {
+        Ok(LogTimer::new(
+            self.timezone.parse()?,
+            self.timestamp_format.parse()?,
+        ))
+    }
+
+    fn filter(&self) -> Result<EnvFilter> {
+        EnvFilter::try_new(&self.filter)
+            .map_err(|e| eyre!("invalid log filter `{}`: {e}", self.filter))
+    }
+}
+
+/// Returned by [`init_tracing`] along with the [`LogHandle`], flushes buffered
+/// logs and spans and stops the background writers when dropped.
+///
+/// Keep it until the process exits, e.g. `let (handle, _guard) = ...` in
+/// `main`, a guard bound to `_` is dropped right away.
+#[must_use = "logs are flushed and written only while the guard is alive"]
+pub struct LogGuard {
+    _workers: sink::Workers,
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("shutdown otel tracer provider failed: {e}");
+            }
         }
     }
 }
 
-pub fn init_tracing(name: &str, log_config: &LogConfig) -> Result<()> {
-    // log
-    let mut logfile = None;
-    let mut stdout = None;
-    if let Some(rolling_file_path) = &log_config.rolling_file_path {
-        // logfile
-        logfile = Some(tracing_appender::rolling::daily(rolling_file_path, name));
-    } else {
-        // stdout
-        stdout = Some(
-            std::io::stdout
-                .with_max_level(tracing::Level::from_str(&log_config.max_level).unwrap()),
-        );
+/// Number of log lines dropped because a sink's buffer was full, only
+/// happens with `non_blocking.lossy` set.
+pub fn dropped_lines() -> usize {
+    sink::dropped_lines()
+}
+
+/// Handle returned by [`init_tracing`] to change the global filter at runtime.
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    /// `LogConfig.filter` last applied, filters set at runtime are kept until it changes
+    config_filter: Arc<Mutex<String>>,
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl LogHandle {
+    pub fn filter(&self) -> Result<String> {
+        self.filter
+            .with_current(|filter| filter.to_string())
+            .map_err(|e| eyre!("get log filter failed: {e}"))
+    }
+
+    pub fn set_filter(&self, filter: &str) -> Result<()> {
+        let filter =
+            EnvFilter::try_new(filter).map_err(|e| eyre!("invalid log filter `{filter}`: {e}"))?;
+        self.filter
+            .reload(filter)
+            .map_err(|e| eyre!("reload log filter failed: {e}"))
     }
 
+    /// Apply the reloadable parts of `log_config`, for use with configuration hot reload.
+    ///
+    /// The filter is only replaced if `log_config.filter` changed since it was
+    /// last applied, so a filter set through [`Self::set_filter`] survives
+    /// reloads of an otherwise changed config.
+    pub fn reload(&self, log_config: &LogConfig) -> Result<()> {
+        let mut config_filter = self
+            .config_filter
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *config_filter != log_config.filter {
+            self.filter
+                .reload(log_config.filter()?)
+                .map_err(|e| eyre!("reload log filter failed: {e}"))?;
+            config_filter.clone_from(&log_config.filter);
+        }
+        Ok(())
+    }
+
+    /// Number of log lines dropped because a sink's buffer was full.
+    pub fn dropped_lines(&self) -> usize {
+        dropped_lines()
+    }
+
+    /// Export all finished spans which have not been exported yet.
+    #[cfg(feature = "otel")]
+    pub fn flush(&self) {
+        if let Some(provider) = &self.tracer_provider {
+            for result in provider.force_flush() {
+                if let Err(e) = result {
+                    eprintln!("flush otel spans failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+pub fn init_tracing(name: &str, log_config: &LogConfig) -> Result<(LogHandle, LogGuard)> {
+    let (subscriber, handle, guard) = subscriber(name, log_config)?;
+    subscriber.try_init()?;
+    Ok((handle, guard))
+}
+
+pub(crate) fn subscriber(
+    name: &str,
+    log_config: &LogConfig,
+) -> Result<(impl tracing::Subscriber + Send + Sync, LogHandle, LogGuard)> {
     // set timer
-    let local_offset_sec = Local::now().offset().fix().local_minus_utc();
-    let utc_offset = UtcOffset::from_whole_seconds(local_offset_sec)
-        .unwrap_or(UtcOffset::from_hms(8, 0, 0).unwrap());
-    let timer = OffsetTime::new(utc_offset, well_known::Rfc3339);
-
-    if let Some(stdout) = stdout {
-        tracing_subscriber::registry()
-            .with(EnvFilter::new(&log_config.filter))
-            .with(
-                tracing_subscriber::fmt::layer()
-                    .event_format(format().compact())
-                    .with_ansi(false)
-                    .with_timer(timer)
-                    .with_writer(stdout),
-            )
-            .try_init()?;
-    } else {
-        tracing_subscriber::registry()
-            .with(EnvFilter::new(&log_config.filter))
-            .with(
-                tracing_subscriber::fmt::layer()
-                    .event_format(format().compact())
-                    .with_ansi(false)
-                    .with_timer(timer)
-                    .with_writer(logfile.unwrap()),
+    let timer = log_config.timer()?;
+    let filter = log_config.filter()?;
+    let redactor = Arc::new(Redactor::new(&log_config.redact)?);
+    let rate_limit = log_config
+        .rate_limit
+        .as_ref()
+        .map(RateLimitLayer::new)
+        .transpose()?;
+
+    // sinks
+    let mut workers = sink::Workers::default();
+    #[allow(unused_mut)]
+    let mut layers = log_config
+        .sinks()?
+        .iter()
+        .map(|sink| {
+            sink.layer(
+                name,
+                log_config.format,
+                timer.clone(),
+                &log_config.non_blocking,
+                &mut workers,
+                redactor.clone(),
             )
-            .try_init()?;
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    #[cfg(feature = "otel")]
+    let tracer_provider = match &log_config.otel {
+        Some(otel_config) => {
+            let (layer, provider) = otel::layer(name, otel_config, redactor.clone())?;
+            layers.push(layer);
+            Some(provider)
+        }
+        None => None,
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(rate_limit)
+        .with(layers);
+
+    Ok((
+        subscriber,
+        LogHandle {
+            filter: handle,
+            config_filter: Arc::new(Mutex::new(log_config.filter.clone())),
+            #[cfg(feature = "otel")]
+            tracer_provider: tracer_provider.clone(),
+        },
+        LogGuard {
+            _workers: workers,
+            #[cfg(feature = "otel")]
+            tracer_provider,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn file_config(dir: &Path) -> LogConfig {
+        LogConfig {
+            rolling_file_path: Some(dir.display().to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn read_logs(dir: &Path) -> String {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect()
     }
 
-    Ok(())
+    #[test]
+    fn guard_flushes_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let (subscriber, _handle, guard) = subscriber("app", &file_config(dir.path())).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..1000 {
+                tracing::info!("line {i}");
+            }
+        });
+        drop(guard);
+        let logs = read_logs(dir.path());
+        assert_eq!(logs.lines().count(), 1000);
+        assert!(logs.contains("line 999"));
+        assert_eq!(dropped_lines(), 0);
+    }
+
+    fn trace_config(filter: &str) -> LogConfig {
+        LogConfig {
+            max_level: "trace".to_owned(),
+            filter: filter.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn set_filter() {
+        let (subscriber, handle, _guard) = subscriber("app", &trace_config("info")).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!tracing::enabled!(tracing::Level::DEBUG));
+            handle.set_filter("debug").unwrap();
+            assert_eq!(handle.filter().unwrap(), "debug");
+            assert!(tracing::enabled!(tracing::Level::DEBUG));
+            let e = handle.set_filter("debug,=").unwrap_err();
+            assert!(e.to_string().contains("invalid log filter"), "{e}");
+            assert_eq!(handle.filter().unwrap(), "debug");
+        });
+    }
+
+    #[test]
+    fn reload_keeps_runtime_filter_until_config_changes() {
+        // displayed by `EnvFilter` in another order
+        let config = trace_config("info,common_rs=debug");
+        let (subscriber, handle, _guard) = subscriber("app", &config).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            handle.set_filter("warn").unwrap();
+            handle.reload(&config).unwrap();
+            assert_eq!(handle.filter().unwrap(), "warn");
+            assert!(!tracing::enabled!(tracing::Level::INFO));
+
+            handle.reload(&trace_config("trace")).unwrap();
+            assert_eq!(handle.filter().unwrap(), "trace");
+            assert!(tracing::enabled!(tracing::Level::TRACE));
+
+            assert!(handle.reload(&trace_config("trace,=")).is_err());
+            assert_eq!(handle.filter().unwrap(), "trace");
+        });
+    }
 }
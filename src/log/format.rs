@@ -0,0 +1,318 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        format::{self, DefaultFields, PrettyFields, Writer},
+        time::FormatTime,
+        FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter,
+    },
+    registry::LookupSpan,
+    Layer,
+};
+
+use super::{RedactEvent, RedactFields, Redactor};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    Full,
+    Json,
+}
+
+/// Build a fmt layer writing `format` to `writer`.
+pub(crate) fn fmt_layer<S, W, T>(
+    name: &str,
+    format: LogFormat,
+    timer: T,
+    writer: W,
+    ansi: bool,
+    redactor: Arc<Redactor>,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    T: FormatTime + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Compact => layer
+            .with_timer(timer)
+            .compact()
+            .fmt_fields(RedactFields::new(DefaultFields::new(), redactor))
+            .boxed(),
+        LogFormat::Pretty => layer
+            .event_format(RedactEvent::new(
+                format::format().pretty().with_timer(timer),
+                redactor.clone(),
+            ))
+            .fmt_fields(RedactFields::new(PrettyFields::new(), redactor))
+            .boxed(),
+        LogFormat::Full => layer
+            .with_timer(timer)
+            .fmt_fields(RedactFields::new(DefaultFields::new(), redactor))
+            .boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new(redactor.clone()))
+            .event_format(JsonFormat::new(name, timer, redactor))
+            .boxed(),
+    }
+}
+
+/// Formats each event as a single line JSON object:
+///
+/// `{"timestamp":..,"level":..,"target":..,"service":..,"fields":{..},"span":{..},"spans":[..]}`
+///
+/// `span` is the innermost span and `spans` lists all from the root, both are
+/// left out for events outside of spans.
+#[derive(Debug)]
+pub struct JsonFormat<T> {
+    service: String,
+    timer: T,
+    redactor: Arc<Redactor>,
+}
+
+impl<T> JsonFormat<T> {
+    pub fn new(service: &str, timer: T, redactor: Arc<Redactor>) -> Self {
+        Self {
+            service: service.to_owned(),
+            timer,
+            redactor,
+        }
+    }
+}
+
+impl<S, N, T> FormatEvent<S, N> for JsonFormat<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+
+        let mut timestamp = String::new();
+        self.timer.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor {
+            fields: &mut fields,
+            redactor: &self.redactor,
+        });
+        let mut line = JsonLine {
+            timestamp,
+            level: metadata.level().as_str(),
+            target: metadata.target(),
+            service: &self.service,
+            fields,
+            span: None,
+            spans: None,
+        };
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans = scope
+                .from_root()
+                .map(|span| {
+                    let mut object = Map::new();
+                    object.insert("name".to_owned(), span.name().into());
+                    if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                        // span fields are recorded by `JsonFields`
+                        if let Ok(Value::Object(fields)) = serde_json::from_str(fields) {
+                            object.extend(fields);
+                        }
+                    }
+                    Value::Object(object)
+                })
+                .collect::<Vec<_>>();
+            line.span = spans.last().cloned();
+            line.spans = Some(spans);
+        }
+
+        let line = serde_json::to_string(&line).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{line}")
+    }
+}
+
+/// A line of [`JsonFormat`], keys in this order.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    service: &'a str,
+    fields: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spans: Option<Vec<Value>>,
+}
+
+/// Records span fields as a JSON object for [`JsonFormat`].
+#[derive(Debug)]
+pub struct JsonFields {
+    redactor: Arc<Redactor>,
+}
+
+impl JsonFields {
+    pub const fn new(redactor: Arc<Redactor>) -> Self {
+        Self { redactor }
+    }
+}
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor {
+            fields: &mut map,
+            redactor: &self.redactor,
+        });
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut map = match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        fields.record(&mut JsonVisitor {
+            fields: &mut map,
+            redactor: &self.redactor,
+        });
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+struct JsonVisitor<'a> {
+    fields: &'a mut Map<String, Value>,
+    redactor: &'a Redactor,
+}
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, mut value: Value) {
+        if !self.redactor.is_empty() {
+            let text = match &value {
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            if let Cow::Owned(redacted) = self.redactor.redact(field.name(), &text) {
+                value = redacted.into();
+            }
+        }
+        self.fields.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::log::memory::MemoryWriter;
+
+    struct FixedTime;
+
+    impl FormatTime for FixedTime {
+        fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+            w.write_str("2024-01-02T03:04:05.000006+08:00")
+        }
+    }
+
+    fn json_lines(log: impl FnOnce()) -> Vec<String> {
+        let writer = MemoryWriter::default();
+        let layer = fmt_layer(
+            "app",
+            LogFormat::Json,
+            FixedTime,
+            writer.clone(),
+            false,
+            Default::default(),
+        );
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), log);
+        writer.contents().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn json_snapshot() {
+        let lines = json_lines(|| {
+            tracing::warn!(target: "json", n = 1, ok = true, "hello");
+            let outer = tracing::info_span!("outer", id = 7);
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("inner", user = tracing::field::Empty);
+            inner.record("user", "a\"b");
+            let _inner = inner.enter();
+            tracing::error!(target: "json", ratio = 0.5, "quote \" backslash \\ newline \n tab \t bell \u{7} 中文");
+        });
+        assert_eq!(
+            lines,
+            [
+                r#"{"timestamp":"2024-01-02T03:04:05.000006+08:00","level":"WARN","target":"json","service":"app","fields":{"message":"hello","n":1,"ok":true}}"#,
+                r#"{"timestamp":"2024-01-02T03:04:05.000006+08:00","level":"ERROR","target":"json","service":"app","fields":{"message":"quote \" backslash \\ newline \n tab \t bell \u0007 中文","ratio":0.5},"span":{"name":"inner","user":"a\"b"},"spans":[{"id":7,"name":"outer"},{"name":"inner","user":"a\"b"}]}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    sync::Arc,
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{writer::BoxMakeWriter, MakeWriter},
+    layer::Context,
+    Layer,
+};
+
+use super::{redact::EventFields, syslog::severity, Redactor};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends each write as one datagram to the journald native protocol socket.
+///
+/// Entries larger than the socket send buffer are rejected by the kernel.
+#[derive(Default)]
+pub struct JournaldWriter {
+    socket: Option<UnixDatagram>,
+}
+
+impl JournaldWriter {
+    fn send(&mut self, entry: &[u8]) -> io::Result<()> {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => {
+                let socket = self.socket.insert(UnixDatagram::unbound()?);
+                socket.connect(JOURNALD_SOCKET)?;
+                socket
+            }
+        };
+        socket.send(entry).map(|_| ())
+    }
+}
+
+impl Write for JournaldWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(e) = self.send(buf) {
+            self.socket = None;
+            return Err(e);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes events as journal entries, event fields become upper case journal
+/// fields prefixed with `F_` like tracing-journald does, so they cannot
+/// replace `MESSAGE`, `PRIORITY` or other fields written by the layer.
+pub(crate) struct JournaldLayer {
+    writer: BoxMakeWriter,
+    identifier: String,
+    redactor: Arc<Redactor>,
+}
+
+impl JournaldLayer {
+    pub(crate) fn new(name: &str, writer: BoxMakeWriter, redactor: Arc<Redactor>) -> Self {
+        Self {
+            writer,
+            identifier: name.to_owned(),
+            redactor,
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let fields = EventFields::new(event, &self.redactor);
+
+        let mut entry = Vec::new();
+        put_field(&mut entry, "MESSAGE", &fields.message);
+        put_field(
+            &mut entry,
+            "PRIORITY",
+            &severity(metadata.level()).to_string(),
+        );
+        put_field(&mut entry, "SYSLOG_IDENTIFIER", &self.identifier);
+        put_field(&mut entry, "TARGET", metadata.target());
+        if let Some(file) = metadata.file() {
+            put_field(&mut entry, "CODE_FILE", file);
+        }
+        if let Some(line) = metadata.line() {
+            put_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        for (name, value) in &fields.fields {
+            put_field(&mut entry, &field_name(name), value);
+        }
+
+        let mut writer = self.writer.make_writer_for(metadata);
+        if let Err(e) = writer.write_all(&entry) {
+            eprintln!("write journald failed: {e}");
+        }
+    }
+}
+
+/// Longest field name journald accepts.
+const MAX_FIELD_NAME: usize = 64;
+
+/// `F_` and the name in upper case letters, digits and underscores, the
+/// prefix keeps names from starting with an underscore, reserved for fields
+/// trusted by journald, or a digit.
+fn field_name(name: &str) -> String {
+    "F_".chars()
+        .chain(name.chars().map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        }))
+        .take(MAX_FIELD_NAME)
+        .collect()
+}
+
+/// Native protocol encoding, values containing newlines are length prefixed.
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::log::memory::MemoryWriter;
+
+    #[test]
+    fn field_names() {
+        assert_eq!(field_name("user_id"), "F_USER_ID");
+        assert_eq!(field_name("PRIORITY"), "F_PRIORITY");
+        assert_eq!(field_name("_hidden"), "F__HIDDEN");
+        assert_eq!(field_name("2fa"), "F_2FA");
+        assert_eq!(field_name("http.method"), "F_HTTP_METHOD");
+        assert_eq!(field_name(&"a".repeat(100)).len(), MAX_FIELD_NAME);
+    }
+
+    #[test]
+    fn user_fields_do_not_replace_own_fields() {
+        let writer = MemoryWriter::default();
+        let layer = JournaldLayer::new(
+            "app",
+            BoxMakeWriter::new(writer.clone()),
+            Default::default(),
+        );
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::warn!(
+                target: "journald",
+                priority = 7,
+                syslog_identifier = "other",
+                _pid = 1,
+                "hello"
+            );
+        });
+        let entry = writer.contents();
+        let fields = entry
+            .lines()
+            .filter(|line| !line.starts_with("CODE_"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "MESSAGE=hello",
+                "PRIORITY=4",
+                "SYSLOG_IDENTIFIER=app",
+                "TARGET=journald",
+                "F_PRIORITY=7",
+                "F_SYSLOG_IDENTIFIER=other",
+                "F__PID=1",
+            ]
+        );
+    }
+
+    #[test]
+    fn multiline_values_are_length_prefixed() {
+        let mut entry = Vec::new();
+        put_field(&mut entry, "MESSAGE", "a\nb");
+        put_field(&mut entry, "F_X", "c");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\nF_X=c\n");
+        assert_eq!(entry, expected);
+    }
+}
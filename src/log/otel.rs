@@ -0,0 +1,302 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    borrow::Cow, collections::BTreeMap, future::Future, pin::Pin, sync::Arc, time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{Status, TracerProvider as _},
+    Context, KeyValue, Value,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use serde::{Deserialize, Serialize};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use super::Redactor;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtelConfig {
+    /// OTLP gRPC collector endpoint
+    endpoint: String,
+    /// export timeout in milliseconds
+    timeout: u64,
+    /// fraction of root traces to sample, children follow their parent's decision
+    sampling_ratio: f64,
+    /// extra resource attributes, `service.name` is set from the service name
+    resource_attributes: BTreeMap<String, String>,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://127.0.0.1:4317".to_owned(),
+            timeout: 10000,
+            sampling_ratio: 1.0,
+            resource_attributes: Default::default(),
+        }
+    }
+}
+
+/// Build a layer exporting spans over OTLP, must be called within a tokio runtime.
+///
+/// Span and event attributes, event messages and error descriptions are
+/// masked through `redactor` like the log sinks.
+pub(crate) fn layer<S>(
+    name: &str,
+    config: &OtelConfig,
+    redactor: Arc<Redactor>,
+) -> Result<(Box<dyn Layer<S> + Send + Sync>, TracerProvider)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    tokio::runtime::Handle::try_current()
+        .map_err(|_| eyre!("otel exporter must be initialized within a tokio runtime"))?;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&config.endpoint)
+        .with_timeout(Duration::from_millis(config.timeout))
+        .build()
+        .map_err(|e| eyre!("build otlp exporter failed: {e}"))?;
+
+    let mut attributes = vec![KeyValue::new("service.name", name.to_owned())];
+    attributes.extend(
+        config
+            .resource_attributes
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+    );
+
+    let builder = TracerProvider::builder();
+    let builder = if redactor.is_empty() {
+        builder.with_batch_exporter(exporter, runtime::Tokio)
+    } else {
+        builder.with_batch_exporter(RedactExporter::new(exporter, redactor), runtime::Tokio)
+    };
+    let provider = builder
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(Resource::new(attributes))
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(name.to_owned()))
+        .boxed();
+    Ok((layer, provider))
+}
+
+/// Wraps a [`SpanExporter`] to mask spans through a [`Redactor`] before export.
+#[derive(Debug)]
+pub(crate) struct RedactExporter<E> {
+    inner: E,
+    redactor: Arc<Redactor>,
+}
+
+impl<E> RedactExporter<E> {
+    pub(crate) const fn new(inner: E, redactor: Arc<Redactor>) -> Self {
+        Self { inner, redactor }
+    }
+
+    /// Mask string values, and values of any type of sensitive fields.
+    fn redact_attribute(&self, attribute: &mut KeyValue) {
+        let name = attribute.key.as_str();
+        let redacted = match &attribute.value {
+            Value::String(value) => self.redactor.redact(name, value.as_str()),
+            _ if self.redactor.is_sensitive(name) => self.redactor.redact(name, ""),
+            _ => return,
+        };
+        if let Cow::Owned(redacted) = redacted {
+            attribute.value = redacted.into();
+        }
+    }
+
+    /// Mask `text` like the message of a log event.
+    fn redact_message(&self, text: &mut Cow<'static, str>) {
+        if let Cow::Owned(redacted) = self.redactor.redact("message", text) {
+            *text = redacted.into();
+        }
+    }
+
+    fn redact(&self, span: &mut SpanData) {
+        span.attributes
+            .iter_mut()
+            .for_each(|attribute| self.redact_attribute(attribute));
+        for event in &mut span.events.events {
+            // tracing events are exported with their message as name
+            self.redact_message(&mut event.name);
+            event
+                .attributes
+                .iter_mut()
+                .for_each(|attribute| self.redact_attribute(attribute));
+        }
+        if let Status::Error { description } = &mut span.status {
+            self.redact_message(description);
+        }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for RedactExporter<E> {
+    fn export(
+        &mut self,
+        mut batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        batch.iter_mut().for_each(|span| self.redact(span));
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn force_flush(&mut self) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// Set the W3C `traceparent` carried by `headers` as the parent of `span`.
+pub fn extract_trace_context(span: &tracing::Span, headers: &HeaderMap) {
+    let parent: Context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Write the W3C `traceparent` of `span` into `headers`, e.g. for outgoing requests:
+///
+/// `inject_trace_context(&tracing::Span::current(), request.headers_mut())`
+pub fn inject_trace_context(span: &tracing::Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Keeps the exported spans in memory, for tests.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InMemoryExporter(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+#[cfg(test)]
+impl InMemoryExporter {
+    pub(crate) fn spans(&self) -> Vec<SpanData> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl SpanExporter for InMemoryExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        self.0.lock().unwrap().extend(batch);
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    fn attribute<'a>(attributes: &'a [KeyValue], name: &str) -> Cow<'a, str> {
+        attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == name)
+            .map(|attribute| attribute.value.as_str())
+            .unwrap_or_else(|| panic!("attribute `{name}` exported"))
+    }
+
+    #[test]
+    fn redact_exported_spans() {
+        let config = serde_json::from_value(json!({
+            "fields": ["password", "pin"],
+            "patterns": ["secret-\\d+"],
+        }))
+        .unwrap();
+        let exported = InMemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(RedactExporter::new(
+                exported.clone(),
+                Arc::new(Redactor::new(&config).unwrap()),
+            ))
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span =
+                tracing::info_span!("login", password = "hunter2", pin = 1234, user = "alice");
+            let _entered = span.enter();
+            tracing::info!(token = "secret-42", "token secret-42 issued");
+        });
+
+        let spans = exported.spans();
+        let span = spans.iter().find(|span| span.name == "login").unwrap();
+        assert_eq!(attribute(&span.attributes, "password"), "******");
+        assert_eq!(attribute(&span.attributes, "pin"), "******");
+        assert_eq!(attribute(&span.attributes, "user"), "alice");
+        let event = &span.events.events[0];
+        assert_eq!(event.name, "token ****** issued");
+        assert_eq!(attribute(&event.attributes, "token"), "******");
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    warn, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Number of distinct events tracked per window, further events are not limited.
+const MAX_KEYS: usize = 10_000;
+
+/// Counters are split by key so threads logging different events rarely
+/// contend on a lock.
+const SHARDS: usize = 16;
+
+/// Limits repeats of identical events, i.e. events from the same callsite with
+/// the same message, e.g.
+///
+/// ```toml
+/// [log_config.rate_limit]
+/// window = 300
+/// burst = 3
+/// ```
+///
+/// Suppressed events are summarized as a warning at the end of each window.
+/// The defaults damp events repeated every few minutes or faster, e.g. a
+/// failing registration heartbeat which logs every `ttl / 2` seconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// window length in seconds
+    window: u64,
+    /// identical events let through per window
+    burst: u64,
+    /// once `burst` is reached keep one out of every `sample` events, 0 to drop them all
+    sample: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            window: 300,
+            burst: 3,
+            sample: 0,
+        }
+    }
+}
+
+struct Counter {
+    metadata: &'static Metadata<'static>,
+    seen: u64,
+    suppressed: u64,
+}
+
+type Key = (Identifier, String);
+
+#[derive(Default)]
+struct Counters {
+    shards: [Mutex<HashMap<Key, Counter>>; SHARDS],
+    hasher: RandomState,
+}
+
+impl Counters {
+    fn shard(&self, key: &Key) -> &Mutex<HashMap<Key, Counter>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    /// Reset the counters and summarize the events suppressed since the last reset.
+    fn reset(&self) {
+        for shard in &self.shards {
+            let expired = match shard.lock() {
+                Ok(mut shard) => std::mem::take(&mut *shard),
+                Err(_) => continue,
+            };
+            // log with the lock released, summaries go through the layer as well
+            for ((_, message), counter) in expired {
+                if counter.suppressed > 0 {
+                    warn!(
+                        suppressed = counter.suppressed,
+                        target = counter.metadata.target(),
+                        level = %counter.metadata.level(),
+                        "suppressed {} similar events: {message}",
+                        counter.suppressed,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Layer dropping events over the configured rate for every subscriber layer.
+pub struct RateLimitLayer {
+    config: RateLimitConfig,
+    counters: Arc<Counters>,
+}
+
+impl RateLimitLayer {
+    /// Build the layer and start the thread reporting suppressed events, which
+    /// exits once the layer is dropped.
+    pub fn new(config: &RateLimitConfig) -> Result<Self> {
+        if config.window == 0 {
+            return Err(eyre!("log rate_limit window must be greater than 0"));
+        }
+        let counters = Arc::new(Counters::default());
+        let window = Duration::from_secs(config.window);
+        let weak = Arc::downgrade(&counters);
+        std::thread::Builder::new()
+            .name("log-rate-limit".to_owned())
+            .spawn(move || report(weak, window))
+            .map_err(|e| eyre!("spawn log rate limit thread failed: {e}"))?;
+        Ok(Self {
+            config: *config,
+            counters,
+        })
+    }
+
+    const fn allow(&self, counter: &mut Counter) -> bool {
+        counter.seen += 1;
+        let over = counter.seen.saturating_sub(self.config.burst);
+        if over == 0 || (self.config.sample != 0 && over.is_multiple_of(self.config.sample)) {
+            true
+        } else {
+            counter.suppressed += 1;
+            false
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RateLimitLayer {
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        // never limit the summaries reported below
+        if metadata.target() == module_path!() {
+            return true;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let key = (metadata.callsite(), visitor.message);
+        let Ok(mut counters) = self.counters.shard(&key).lock() else {
+            return true;
+        };
+        if let Some(counter) = counters.get_mut(&key) {
+            return self.allow(counter);
+        }
+        if counters.len() < MAX_KEYS / SHARDS {
+            let mut counter = Counter {
+                metadata,
+                seen: 0,
+                suppressed: 0,
+            };
+            let allowed = self.allow(&mut counter);
+            counters.insert(key, counter);
+            allowed
+        } else {
+            true
+        }
+    }
+}
+
+/// Every `window`, reset the counters and summarize the events suppressed in it.
+fn report(counters: Weak<Counters>, window: Duration) {
+    loop {
+        std::thread::sleep(window);
+        let Some(counters) = counters.upgrade() else {
+            return;
+        };
+        counters.reset();
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::info;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut visitor = MessageVisitor::default();
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.message);
+        }
+    }
+
+    impl Capture {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    /// Run `f` with a limited subscriber, `f` may reset the counters to end a window.
+    fn with_rate_limit(burst: u64, sample: u64, f: impl FnOnce(&dyn Fn())) -> Vec<String> {
+        let config = RateLimitConfig {
+            // windows are ended by the test
+            window: 3600,
+            burst,
+            sample,
+        };
+        let layer = RateLimitLayer::new(&config).unwrap();
+        let counters = layer.counters.clone();
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(layer)
+            .with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || f(&|| counters.reset()));
+        capture.take()
+    }
+
+    #[test]
+    fn suppress_over_burst() {
+        let logged = with_rate_limit(3, 0, |_| {
+            for i in 0..10 {
+                info!("heartbeat failed");
+                info!("attempt {i}");
+            }
+        });
+        let heartbeats = logged.iter().filter(|m| *m == "heartbeat failed").count();
+        assert_eq!(heartbeats, 3);
+        // different messages are counted apart
+        assert_eq!(logged.len(), 3 + 10);
+    }
+
+    #[test]
+    fn sample_over_burst() {
+        let logged = with_rate_limit(2, 3, |_| {
+            for _ in 0..10 {
+                info!("heartbeat failed");
+            }
+        });
+        // the 1st, 2nd, 5th and 8th
+        assert_eq!(logged.len(), 4);
+    }
+
+    #[test]
+    fn summarize_and_reset_window() {
+        let logged = with_rate_limit(3, 0, |reset| {
+            for _ in 0..10 {
+                info!("heartbeat failed");
+            }
+            reset();
+            for _ in 0..3 {
+                info!("heartbeat failed");
+            }
+            // nothing suppressed, no summary
+            reset();
+        });
+        let mut expected = vec!["heartbeat failed"; 3];
+        expected.push("suppressed 7 similar events: heartbeat failed");
+        expected.extend(["heartbeat failed"; 3]);
+        assert_eq!(logged, expected);
+    }
+}
@@ -0,0 +1,590 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{borrow::Cow, collections::HashSet, fmt, sync::Arc};
+
+use color_eyre::{eyre::eyre, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{
+    field::{debug, display, DebugValue, DisplayValue, Field, Value, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::{MakeVisitor, VisitFmt, VisitOutput},
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Masks sensitive event and span fields before they are written by any sink
+/// or exported over OTLP, e.g.
+///
+/// ```toml
+/// [log_config.redact]
+/// fields = ["password", "private_key"]
+/// patterns = ["(0x)?[0-9a-fA-F]{64}", "(?i)bearer\\s+[\\w\\-.~+/]+=*"]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// field names (case insensitive) whose values are masked entirely
+    fields: Vec<String>,
+    /// regular expressions masked wherever they match in a field value or message
+    patterns: Vec<String>,
+    mask: String,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        Self {
+            fields: Default::default(),
+            patterns: Default::default(),
+            mask: "******".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Redactor {
+    fields: HashSet<String>,
+    patterns: Vec<Regex>,
+    mask: String,
+}
+
+impl Redactor {
+    pub fn new(config: &RedactConfig) -> Result<Self> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| eyre!("invalid log redact pattern `{pattern}`: {e}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            fields: config
+                .fields
+                .iter()
+                .map(|field| field.to_ascii_lowercase())
+                .collect(),
+            patterns,
+            mask: config.mask.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.patterns.is_empty()
+    }
+
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        !self.fields.is_empty() && self.fields.contains(&name.to_ascii_lowercase())
+    }
+
+    /// Mask `value` of field `name`, borrowed when nothing needs to be masked.
+    pub fn redact<'a>(&self, name: &str, value: &'a str) -> Cow<'a, str> {
+        if self.is_sensitive(name) {
+            return Cow::Owned(self.mask.clone());
+        }
+        let mut value = Cow::Borrowed(value);
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&value, self.mask.as_str()) {
+                value = Cow::Owned(replaced);
+            }
+        }
+        value
+    }
+}
+
+/// Wraps a field formatter such as `DefaultFields` to mask values through a [`Redactor`].
+#[derive(Debug, Clone)]
+pub struct RedactFields<M> {
+    inner: M,
+    redactor: Arc<Redactor>,
+}
+
+impl<M> RedactFields<M> {
+    pub const fn new(inner: M, redactor: Arc<Redactor>) -> Self {
+        Self { inner, redactor }
+    }
+}
+
+impl<T, M: MakeVisitor<T>> MakeVisitor<T> for RedactFields<M> {
+    type Visitor = RedactVisitor<M::Visitor>;
+
+    fn make_visitor(&self, target: T) -> Self::Visitor {
+        RedactVisitor {
+            inner: self.inner.make_visitor(target),
+            redactor: self.redactor.clone(),
+        }
+    }
+}
+
+pub struct RedactVisitor<V> {
+    inner: V,
+    redactor: Arc<Redactor>,
+}
+
+impl<V: Visit> Visit for RedactVisitor<V> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if self.redactor.is_empty() {
+            self.inner.record_f64(field, value)
+        } else {
+            self.record_debug(field, &value)
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if self.redactor.is_empty() {
+            self.inner.record_i64(field, value)
+        } else {
+            self.record_debug(field, &value)
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if self.redactor.is_empty() {
+            self.inner.record_u64(field, value)
+        } else {
+            self.record_debug(field, &value)
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if self.redactor.is_sensitive(field.name()) {
+            let mask = &self.redactor.mask;
+            self.inner.record_debug(field, &format_args!("{mask}"))
+        } else {
+            self.inner.record_bool(field, value)
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = self.redactor.redact(field.name(), value);
+        self.inner.record_str(field, &value)
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.redactor.is_empty() {
+            return self.inner.record_debug(field, value);
+        }
+        let formatted = format!("{value:?}");
+        match self.redactor.redact(field.name(), &formatted) {
+            Cow::Borrowed(_) => self.inner.record_debug(field, value),
+            Cow::Owned(redacted) => self.inner.record_debug(field, &format_args!("{redacted}")),
+        }
+    }
+}
+
+impl<V: VisitOutput<O>, O> VisitOutput<O> for RedactVisitor<V> {
+    fn finish(self) -> O {
+        self.inner.finish()
+    }
+}
+
+impl<V: VisitFmt> VisitFmt for RedactVisitor<V> {
+    fn writer(&mut self) -> &mut dyn fmt::Write {
+        self.inner.writer()
+    }
+}
+
+/// Formats events with `inner` after masking their fields, for event formatters
+/// like `Pretty` which record event fields without going through `FormatFields`.
+#[derive(Debug)]
+pub struct RedactEvent<F> {
+    inner: F,
+    redactor: Arc<Redactor>,
+}
+
+impl<F> RedactEvent<F> {
+    pub const fn new(inner: F, redactor: Arc<Redactor>) -> Self {
+        Self { inner, redactor }
+    }
+}
+
+/// The most fields the `tracing` macros accept for a single event.
+const MAX_FIELDS: usize = 32;
+
+impl<S, N, F> FormatEvent<S, N> for RedactEvent<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let Some(first) = metadata.fields().iter().next() else {
+            return self.inner.format_event(ctx, writer, event);
+        };
+        if self.redactor.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        let mut recorder = Recorder {
+            redactor: &self.redactor,
+            values: Vec::new(),
+        };
+        event.record(&mut recorder);
+
+        let mut entries: [(&Field, Option<&dyn Value>); MAX_FIELDS] = [(&first, None); MAX_FIELDS];
+        for (entry, (field, value)) in entries.iter_mut().zip(recorder.values.iter()) {
+            *entry = (field, Some(value.as_value()));
+        }
+        let values = metadata.fields().value_set(&entries);
+        let redacted = if event.is_contextual() {
+            Event::new(metadata, &values)
+        } else {
+            Event::new_child_of(event.parent().cloned(), metadata, &values)
+        };
+        self.inner.format_event(ctx, writer, &redacted)
+    }
+}
+
+enum Recorded {
+    Str(DebugValue<String>),
+    Debug(DisplayValue<String>),
+    F64(f64),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+}
+
+impl Recorded {
+    fn as_value(&self) -> &dyn Value {
+        match self {
+            Self::Str(value) => value,
+            Self::Debug(value) => value,
+            Self::F64(value) => value,
+            Self::I64(value) => value,
+            Self::U64(value) => value,
+            Self::Bool(value) => value,
+        }
+    }
+}
+
+struct Recorder<'a> {
+    redactor: &'a Redactor,
+    values: Vec<(Field, Recorded)>,
+}
+
+impl Recorder<'_> {
+    fn push(&mut self, field: &Field, value: Recorded) {
+        let value = if self.redactor.is_sensitive(field.name()) {
+            Recorded::Debug(display(self.redactor.mask.clone()))
+        } else {
+            value
+        };
+        self.values.push((field.clone(), value));
+    }
+
+    /// Numbers are kept unless a pattern masks part of them.
+    fn push_number(&mut self, field: &Field, number: &dyn fmt::Debug, value: Recorded) {
+        let formatted = format!("{number:?}");
+        match self.redactor.redact(field.name(), &formatted) {
+            Cow::Borrowed(_) => self.push(field, value),
+            Cow::Owned(redacted) => self.push(field, Recorded::Debug(display(redacted))),
+        }
+    }
+}
+
+impl Visit for Recorder<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push_number(field, &value, Recorded::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push_number(field, &value, Recorded::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push_number(field, &value, Recorded::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Recorded::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = self.redactor.redact(field.name(), value).into_owned();
+        self.push(field, Recorded::Str(debug(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let formatted = format!("{value:?}");
+        let value = self.redactor.redact(field.name(), &formatted).into_owned();
+        self.push(field, Recorded::Debug(display(value)));
+    }
+}
+
+/// Message and other fields of an event as redacted strings, for sinks which
+/// encode fields themselves.
+#[derive(Default)]
+pub(crate) struct EventFields {
+    pub(crate) message: String,
+    pub(crate) fields: Vec<(&'static str, String)>,
+}
+
+impl EventFields {
+    pub(crate) fn new(event: &Event<'_>, redactor: &Redactor) -> Self {
+        let mut visitor = EventFieldsVisitor {
+            fields: Self::default(),
+            redactor,
+        };
+        event.record(&mut visitor);
+        visitor.fields
+    }
+}
+
+struct EventFieldsVisitor<'a> {
+    fields: EventFields,
+    redactor: &'a Redactor,
+}
+
+impl Visit for EventFieldsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = self.redactor.redact(field.name(), value).into_owned();
+        if field.name() == "message" {
+            self.fields.message = value;
+        } else {
+            self.fields.fields.push((field.name(), value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, Layer, Registry};
+
+    use super::*;
+    use crate::log::{
+        format::fmt_layer, memory::MemoryWriter, syslog::SyslogLayer, LogConfig, LogFormat,
+        SyslogConfig,
+    };
+
+    const MASK: &str = "***";
+
+    fn redactor() -> Arc<Redactor> {
+        let config = RedactConfig {
+            fields: vec![
+                "password".to_owned(),
+                "PIN".to_owned(),
+                "admin".to_owned(),
+                "ratio".to_owned(),
+            ],
+            patterns: vec!["0x[0-9a-f]{8}".to_owned(), "[0-9]{7}".to_owned()],
+            mask: MASK.to_owned(),
+        };
+        Arc::new(Redactor::new(&config).unwrap())
+    }
+
+    /// Log an event with sensitive fields of every kind inside a span with one.
+    fn log(layer: impl Layer<Registry> + Send + Sync) {
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", password = "span-secret");
+            let _enter = span.enter();
+            tracing::info!(
+                password = "str-secret",
+                pin = 424242,
+                admin = true,
+                ratio = 0.25,
+                number = 1234567,
+                account = ?["0xdeadbeef"],
+                kept = "visible",
+                "paid with 0xcafebabe"
+            );
+        });
+    }
+
+    /// Assert that no sensitive value is left in `output`.
+    fn assert_redacted(output: &str) {
+        for secret in [
+            "span-secret",
+            "str-secret",
+            "424242",
+            "true",
+            "0.25",
+            "1234567",
+            "0xdeadbeef",
+            "0xcafebabe",
+        ] {
+            assert!(!output.contains(secret), "`{secret}` in {output}");
+        }
+        assert!(output.contains("visible"), "{output}");
+        assert!(output.contains(MASK), "{output}");
+    }
+
+    fn fmt_output(format: LogFormat) -> String {
+        let writer = MemoryWriter::default();
+        let timer = LogConfig::default().timer().unwrap();
+        log(fmt_layer(
+            "app",
+            format,
+            timer,
+            writer.clone(),
+            false,
+            redactor(),
+        ));
+        writer.contents()
+    }
+
+    #[test]
+    fn compact() {
+        let output = fmt_output(LogFormat::Compact);
+        assert_redacted(&output);
+        assert!(output.contains("pin=***"), "{output}");
+        assert!(output.contains("admin=***"), "{output}");
+    }
+
+    #[test]
+    fn full() {
+        let output = fmt_output(LogFormat::Full);
+        assert_redacted(&output);
+        assert!(output.contains(r#"request{password="***"}"#), "{output}");
+    }
+
+    #[test]
+    fn pretty() {
+        let output = fmt_output(LogFormat::Pretty);
+        assert_redacted(&output);
+        assert!(output.contains("ratio: ***"), "{output}");
+        assert!(output.contains("number: ***"), "{output}");
+    }
+
+    #[test]
+    fn json() {
+        let output = fmt_output(LogFormat::Json);
+        assert_redacted(&output);
+        let line: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(line["fields"]["admin"], MASK);
+        assert_eq!(line["fields"]["pin"], MASK);
+        assert_eq!(line["fields"]["number"], MASK);
+        assert_eq!(line["fields"]["message"], "paid with ***");
+        assert_eq!(line["span"]["password"], MASK);
+    }
+
+    #[test]
+    fn syslog() {
+        let writer = MemoryWriter::default();
+        log(SyslogLayer::new(
+            "app",
+            &SyslogConfig::default(),
+            LogConfig::default().timer().unwrap(),
+            BoxMakeWriter::new(writer.clone()),
+            redactor(),
+        ));
+        let output = writer.contents();
+        assert_redacted(&output);
+        assert!(output.contains(r#"admin="***""#), "{output}");
+        assert!(output.ends_with("paid with ***"), "{output}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn journald() {
+        let writer = MemoryWriter::default();
+        log(crate::log::journald::JournaldLayer::new(
+            "app",
+            BoxMakeWriter::new(writer.clone()),
+            redactor(),
+        ));
+        let output = writer.contents();
+        assert_redacted(&output);
+        assert!(output.contains("\nF_PIN=***\n"), "{output}");
+        assert!(output.starts_with("MESSAGE=paid with ***\n"), "{output}");
+    }
+
+    #[test]
+    fn pretty_rebuilds_events_with_the_most_fields() {
+        let writer = MemoryWriter::default();
+        let timer = LogConfig::default().timer().unwrap();
+        let layer = fmt_layer(
+            "app",
+            LogFormat::Pretty,
+            timer,
+            writer.clone(),
+            false,
+            redactor(),
+        );
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            // 32 fields with the message
+            tracing::info!(
+                f0 = 0,
+                f1 = 1,
+                f2 = 2,
+                f3 = 3,
+                f4 = 4,
+                f5 = 5,
+                f6 = 6,
+                f7 = 7,
+                f8 = 8,
+                f9 = 9,
+                f10 = 10,
+                f11 = 11,
+                f12 = 12,
+                f13 = 13,
+                f14 = 14,
+                f15 = 15,
+                f16 = 16,
+                f17 = 17,
+                f18 = 18,
+                f19 = 19,
+                f20 = 20,
+                f21 = 21,
+                f22 = 22,
+                f23 = 23,
+                f24 = 24,
+                f25 = 25,
+                f26 = 26,
+                f27 = 27,
+                f28 = 28,
+                f29 = 29,
+                password = "last-secret",
+                "message"
+            );
+        });
+        let output = writer.contents();
+        for i in 0..30 {
+            assert!(output.contains(&format!("f{i}: {i}")), "f{i} in {output}");
+        }
+        assert!(output.contains("password: ***"), "{output}");
+        assert!(!output.contains("last-secret"), "{output}");
+    }
+
+    #[test]
+    fn unredacted_without_config() {
+        let writer = MemoryWriter::default();
+        let timer = LogConfig::default().timer().unwrap();
+        log(fmt_layer(
+            "app",
+            LogFormat::Pretty,
+            timer,
+            writer.clone(),
+            false,
+            Default::default(),
+        ));
+        let output = writer.contents();
+        assert!(output.contains("str-secret"), "{output}");
+        assert!(output.contains("pin: 424242"), "{output}");
+    }
+}
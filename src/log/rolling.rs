@@ -0,0 +1,658 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    time::SystemTime,
+};
+
+use chrono::{
+    format::{Parsed, StrftimeItems},
+    Utc,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::fmt::MakeWriter;
+
+use super::LogTimezone;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPolicy {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl RotationPolicy {
+    const fn date_format(&self) -> Option<&'static str> {
+        match self {
+            Self::Minutely => Some("%Y-%m-%d-%H-%M"),
+            Self::Hourly => Some("%Y-%m-%d-%H"),
+            Self::Daily => Some("%Y-%m-%d"),
+            Self::Never => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RotationConfig {
+    policy: RotationPolicy,
+    /// rotate the active file once it grows beyond this many bytes
+    max_file_size: Option<u64>,
+    /// number of log files (including the active one) to keep
+    max_files: Option<usize>,
+    /// total bytes of log files (including the active one) to keep
+    max_total_size: Option<u64>,
+    /// gzip rotated files
+    compress: bool,
+    /// defaults to the service name
+    file_name_prefix: Option<String>,
+    file_name_suffix: Option<String>,
+}
+
+/// A rolling file writer supporting time and size based rotation with retention limits.
+///
+/// Files are named `{prefix}.{date}[.{index}][.{suffix}]`, rotated files get an
+/// additional `.gz` extension when compression is enabled.
+#[derive(Debug)]
+pub struct RollingFileWriter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    dir: PathBuf,
+    prefix: String,
+    suffix: Option<String>,
+    policy: RotationPolicy,
+    max_file_size: Option<u64>,
+    timezone: LogTimezone,
+    period: String,
+    next_check: i64,
+    file: Option<File>,
+    size: u64,
+    cleaner: mpsc::Sender<Cleanup>,
+}
+
+impl RollingFileWriter {
+    pub fn new(
+        dir: impl AsRef<Path>,
+        config: &RotationConfig,
+        name: &str,
+        timezone: LogTimezone,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let prefix = config
+            .file_name_prefix
+            .clone()
+            .unwrap_or_else(|| name.to_owned());
+        let suffix = config
+            .file_name_suffix
+            .clone()
+            .filter(|suffix| !suffix.is_empty());
+
+        // compression and pruning run on a single thread so they never race each other
+        let (cleaner, jobs) = mpsc::channel();
+        let worker = Cleaner {
+            dir: dir.clone(),
+            prefix: prefix.clone(),
+            suffix: suffix.clone(),
+            date_format: config.policy.date_format(),
+            max_files: config.max_files.filter(|files| *files > 0),
+            max_total_size: config.max_total_size.filter(|size| *size > 0),
+            compress: config.compress,
+        };
+        std::thread::Builder::new()
+            .name("log-cleaner".to_owned())
+            .spawn(move || worker.run(jobs))?;
+
+        let now = Utc::now().timestamp();
+        let mut state = State {
+            dir,
+            prefix,
+            suffix,
+            policy: config.policy,
+            max_file_size: config.max_file_size.filter(|size| *size > 0),
+            timezone,
+            period: String::new(),
+            next_check: next_minute(now),
+            file: None,
+            size: 0,
+            cleaner,
+        };
+        state.period = state.current_period();
+        state.open()?;
+        state.cleanup(None);
+
+        Ok(Self {
+            state: Mutex::new(state),
+        })
+    }
+}
+
+impl State {
+    fn current_period(&self) -> String {
+        self.policy
+            .date_format()
+            .map(|format| self.timezone.now().format(format).to_string())
+            .unwrap_or_default()
+    }
+
+    fn file_name(&self, index: Option<usize>) -> String {
+        let mut name = self.prefix.clone();
+        if !self.period.is_empty() {
+            name.push('.');
+            name.push_str(&self.period);
+        }
+        if let Some(index) = index {
+            name.push_str(&format!(".{index}"));
+        }
+        if let Some(suffix) = &self.suffix {
+            name.push('.');
+            name.push_str(suffix);
+        }
+        name
+    }
+
+    fn active_path(&self) -> PathBuf {
+        self.dir.join(self.file_name(None))
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.active_path())?;
+        self.size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn roll_period(&mut self, period: String) -> io::Result<()> {
+        self.file.take();
+        let rotated = self.active_path();
+        self.period = period;
+        self.open()?;
+        self.cleanup(Some(rotated));
+        Ok(())
+    }
+
+    /// Index after the highest one of the current period, so that indexes
+    /// keep growing with age even after older files were pruned.
+    fn next_index(&self) -> io::Result<usize> {
+        let active = self.file_name(None);
+        let suffix = self
+            .suffix
+            .as_ref()
+            .map(|suffix| format!(".{suffix}"))
+            .unwrap_or_default();
+        let base = format!("{}.", &active[..active.len() - suffix.len()]);
+        let mut max = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let name = name.strip_suffix(".gz").unwrap_or(&name);
+            if let Some(index) = name
+                .strip_prefix(&base)
+                .and_then(|rest| rest.strip_suffix(&suffix))
+                .and_then(|index| index.parse::<usize>().ok())
+            {
+                max = max.max(index);
+            }
+        }
+        Ok(max + 1)
+    }
+
+    fn roll_size(&mut self) -> io::Result<()> {
+        self.file.take();
+        let active = self.active_path();
+        let rotated = self.dir.join(self.file_name(Some(self.next_index()?)));
+        fs::rename(&active, &rotated)?;
+        self.open()?;
+        self.cleanup(Some(rotated));
+        Ok(())
+    }
+
+    /// Compress the rotated file and apply retention limits off the write path.
+    fn cleanup(&self, rotated: Option<PathBuf>) {
+        let _ = self.cleaner.send(Cleanup {
+            rotated,
+            active: self.active_path(),
+        });
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Utc::now().timestamp();
+        if now >= self.next_check {
+            self.next_check = next_minute(now);
+            let period = self.current_period();
+            if period != self.period {
+                self.roll_period(period)?;
+            }
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_file_size {
+                self.roll_size()?;
+            }
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let written = self
+            .file
+            .as_mut()
+            .map(|file| file.write(buf))
+            .unwrap_or(Ok(0))?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .as_mut()
+            .map(|file| file.flush())
+            .unwrap_or(Ok(()))
+    }
+}
+
+struct Cleanup {
+    rotated: Option<PathBuf>,
+    active: PathBuf,
+}
+
+struct Cleaner {
+    dir: PathBuf,
+    prefix: String,
+    suffix: Option<String>,
+    date_format: Option<&'static str>,
+    max_files: Option<usize>,
+    max_total_size: Option<u64>,
+    compress: bool,
+}
+
+impl Cleaner {
+    fn run(self, jobs: mpsc::Receiver<Cleanup>) {
+        for job in jobs {
+            if let Some(rotated) = job.rotated.filter(|_| self.compress) {
+                if let Err(e) = compress_file(&rotated).or_else(ignore_not_found) {
+                    eprintln!("compress log file {} failed: {e}", rotated.display());
+                }
+            }
+            self.prune(&job.active);
+        }
+    }
+
+    /// Whether `name` is one the writer produces, `{prefix}[.{period}][.{index}][.{suffix}][.gz]`,
+    /// so that other files in the directory are never pruned.
+    fn is_log_file(&self, name: &str) -> bool {
+        let Some(rest) = name.strip_prefix(&self.prefix) else {
+            return false;
+        };
+        let rest = rest.strip_suffix(".gz").unwrap_or(rest);
+        let rest = match &self.suffix {
+            Some(suffix) => match rest
+                .strip_suffix(suffix.as_str())
+                .and_then(|rest| rest.strip_suffix('.'))
+            {
+                Some(rest) => rest,
+                None => return false,
+            },
+            None => rest,
+        };
+        let mut parts = rest.split('.');
+        // `rest` is empty or starts with a dot
+        if parts.next() != Some("") {
+            return false;
+        }
+        if let Some(format) = self.date_format {
+            let Some(period) = parts.next() else {
+                return false;
+            };
+            let mut parsed = Parsed::new();
+            if chrono::format::parse(&mut parsed, period, StrftimeItems::new(format)).is_err() {
+                return false;
+            }
+        }
+        match (parts.next(), parts.next()) {
+            (None, _) => true,
+            (Some(index), None) => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+            _ => false,
+        }
+    }
+
+    fn prune(&self, active: &Path) {
+        if self.max_files.is_none() && self.max_total_size.is_none() {
+            return;
+        }
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("read log dir {} failed: {e}", self.dir.display());
+                return;
+            }
+        };
+        let mut files = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry.path() != active
+                    && entry.file_type().map(|ty| ty.is_file()).unwrap_or(false)
+                    && self.is_log_file(&entry.file_name().to_string_lossy())
+            })
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((entry.path(), modified, meta.len()))
+            })
+            .collect::<Vec<_>>();
+        // newest first
+        files.sort_by_key(|file| std::cmp::Reverse(file.1));
+
+        let mut kept_files = 1;
+        let mut kept_size = fs::metadata(active).map(|m| m.len()).unwrap_or(0);
+        for (path, _, len) in files {
+            kept_files += 1;
+            kept_size += len;
+            let over_files = self.max_files.is_some_and(|max| kept_files > max);
+            let over_size = self.max_total_size.is_some_and(|max| kept_size > max);
+            if over_files || over_size {
+                if let Err(e) = fs::remove_file(&path).or_else(ignore_not_found) {
+                    eprintln!("remove log file {} failed: {e}", path.display());
+                }
+                kept_files -= 1;
+                kept_size -= len;
+            }
+        }
+    }
+}
+
+const fn next_minute(timestamp: i64) -> i64 {
+    (timestamp / 60 + 1) * 60
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// A rotated file may already be pruned before it gets compressed.
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::NotFound {
+        Ok(())
+    } else {
+        Err(e)
+    }
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let modified = input.metadata()?.modified()?;
+    let output = File::create(gz_path(path))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    let output = encoder.finish()?;
+    // keep the original mtime so retention still removes the oldest files first
+    output.set_modified(modified)?;
+    output.sync_all()?;
+    fs::remove_file(path)
+}
+
+impl Write for RollingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &RollingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileWriter {
+    type Writer = &'a RollingFileWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, io::Read, time::Duration};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn config(policy: RotationPolicy) -> RotationConfig {
+        RotationConfig {
+            policy,
+            file_name_suffix: Some("log".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn writer(dir: &Path, config: &RotationConfig) -> RollingFileWriter {
+        RollingFileWriter::new(dir, config, "app", LogTimezone::Utc).unwrap()
+    }
+
+    fn write_line(writer: &RollingFileWriter, line: &str) {
+        writeln!(&mut &*writer, "{line}").unwrap();
+        // distinct modification times keep the retention order stable
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    fn names(dir: &Path) -> BTreeSet<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        if path.extension().is_some_and(|extension| extension == "gz") {
+            GzDecoder::new(File::open(path).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+        } else {
+            content = fs::read_to_string(path).unwrap();
+        }
+        content
+    }
+
+    /// Wait for the cleaner thread to bring the directory into the expected state.
+    fn wait_for_names(dir: &Path, expected: &[&str]) {
+        let expected = expected.iter().map(|name| name.to_string()).collect();
+        for _ in 0..500 {
+            if names(dir) == expected {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(dir), expected);
+    }
+
+    #[test]
+    fn size_rollover() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_file_size: Some(10),
+            ..config(RotationPolicy::Never)
+        };
+        let writer = writer(dir.path(), &config);
+        for line in ["first", "second", "third"] {
+            write_line(&writer, line);
+        }
+        wait_for_names(dir.path(), &["app.1.log", "app.2.log", "app.log"]);
+        assert_eq!(read(&dir.path().join("app.1.log")), "first\n");
+        assert_eq!(read(&dir.path().join("app.2.log")), "second\n");
+        assert_eq!(read(&dir.path().join("app.log")), "third\n");
+    }
+
+    #[test]
+    fn period_rollover() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = writer(dir.path(), &config(RotationPolicy::Daily));
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        {
+            // pretend the active file was opened on an earlier day
+            let mut state = writer.state.lock().unwrap();
+            state.period = "2000-01-01".to_owned();
+            state.open().unwrap();
+        }
+        write_line(&writer, "yesterday");
+        // the period is checked once a minute
+        writer.state.lock().unwrap().next_check = 0;
+        write_line(&writer, "today");
+        let today_name = format!("app.{today}.log");
+        wait_for_names(dir.path(), &["app.2000-01-01.log", &today_name]);
+        assert_eq!(read(&dir.path().join("app.2000-01-01.log")), "yesterday\n");
+        assert_eq!(read(&dir.path().join(today_name)), "today\n");
+    }
+
+    #[test]
+    fn max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_file_size: Some(10),
+            max_files: Some(3),
+            ..config(RotationPolicy::Never)
+        };
+        let writer = writer(dir.path(), &config);
+        for line in ["line 01", "line 02", "line 03", "line 04", "line 05"] {
+            write_line(&writer, line);
+        }
+        wait_for_names(dir.path(), &["app.3.log", "app.4.log", "app.log"]);
+        assert_eq!(read(&dir.path().join("app.4.log")), "line 04\n");
+        assert_eq!(read(&dir.path().join("app.log")), "line 05\n");
+    }
+
+    #[test]
+    fn max_total_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_file_size: Some(10),
+            max_total_size: Some(20),
+            ..config(RotationPolicy::Never)
+        };
+        let writer = writer(dir.path(), &config);
+        // 8 bytes per file, only two of them fit
+        for line in ["line 01", "line 02", "line 03", "line 04"] {
+            write_line(&writer, line);
+        }
+        wait_for_names(dir.path(), &["app.3.log", "app.log"]);
+        assert_eq!(read(&dir.path().join("app.3.log")), "line 03\n");
+    }
+
+    #[test]
+    fn compress_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_file_size: Some(10),
+            max_files: Some(2),
+            compress: true,
+            ..config(RotationPolicy::Never)
+        };
+        let writer = writer(dir.path(), &config);
+        for line in ["first", "second", "third"] {
+            write_line(&writer, line);
+        }
+        wait_for_names(dir.path(), &["app.2.log.gz", "app.log"]);
+        assert_eq!(read(&dir.path().join("app.2.log.gz")), "second\n");
+    }
+
+    #[test]
+    fn skip_foreign_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let foreign = [
+            "app.toml",
+            "app.worker.2024-01-01.log",
+            "app.2024-01-01.x.log",
+            "app.2024-13-01.log",
+            "app.2024-01-01.log.bak",
+            "application.2024-01-01.log",
+            "other.log",
+        ];
+        for name in foreign {
+            fs::write(dir.path().join(name), "foreign\n").unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        let config = RotationConfig {
+            max_file_size: Some(10),
+            max_files: Some(1),
+            ..config(RotationPolicy::Daily)
+        };
+        let writer = writer(dir.path(), &config);
+        for line in ["first", "second", "third"] {
+            write_line(&writer, line);
+        }
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        let active = format!("app.{today}.log");
+        let mut expected = foreign.to_vec();
+        expected.push(&active);
+        wait_for_names(dir.path(), &expected);
+    }
+
+    #[test]
+    fn log_file_names() {
+        let cleaner = |suffix: Option<&str>, policy: RotationPolicy| Cleaner {
+            dir: PathBuf::new(),
+            prefix: "app".to_owned(),
+            suffix: suffix.map(str::to_owned),
+            date_format: policy.date_format(),
+            max_files: None,
+            max_total_size: None,
+            compress: false,
+        };
+        let hourly = cleaner(Some("log"), RotationPolicy::Hourly);
+        assert!(hourly.is_log_file("app.2024-01-01-23.log"));
+        assert!(hourly.is_log_file("app.2024-01-01-23.12.log"));
+        assert!(hourly.is_log_file("app.2024-01-01-23.12.log.gz"));
+        assert!(!hourly.is_log_file("app.2024-01-01.log"));
+        assert!(!hourly.is_log_file("app.2024-01-01-23"));
+        assert!(!hourly.is_log_file("app.2024-01-01-23.x.log"));
+
+        let never = cleaner(None, RotationPolicy::Never);
+        assert!(never.is_log_file("app"));
+        assert!(never.is_log_file("app.3"));
+        assert!(never.is_log_file("app.3.gz"));
+        assert!(!never.is_log_file("app.toml"));
+        assert!(!never.is_log_file("app."));
+        assert!(!never.is_log_file("apps"));
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::Write,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tracing::Subscriber;
+use tracing_appender::non_blocking::{ErrorCounter, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, registry::LookupSpan, EnvFilter, Layer};
+
+#[cfg(target_os = "linux")]
+use super::journald::{JournaldLayer, JournaldWriter};
+use super::{
+    format, syslog::SyslogLayer, LogFormat, LogTimer, Redactor, RollingFileWriter, RotationConfig,
+    SyslogConfig,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    #[default]
+    Stdout,
+    Stderr,
+    File,
+    /// RFC 5424 syslog, `format` and `ansi` do not apply
+    Syslog,
+    /// native journald protocol, `format` and `ansi` do not apply
+    #[cfg(target_os = "linux")]
+    Journald,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NonBlockingConfig {
+    /// write logs from a background thread instead of the logging thread
+    enabled: bool,
+    /// number of lines buffered for the background thread
+    buffered_lines_limit: usize,
+    /// drop lines when the buffer is full instead of blocking the logging thread
+    lossy: bool,
+}
+
+impl Default for NonBlockingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            buffered_lines_limit: 128_000,
+            lossy: false,
+        }
+    }
+}
+
+/// Dropped line counters of all non-blocking writers, kept past their workers
+/// so that the metrics stay monotonic.
+static ERROR_COUNTERS: Mutex<Vec<ErrorCounter>> = Mutex::new(Vec::new());
+
+pub(crate) fn dropped_lines() -> usize {
+    ERROR_COUNTERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|counter| counter.dropped_lines())
+        .sum()
+}
+
+/// Background writer threads of all sinks, flushed when dropped.
+#[derive(Default)]
+pub(crate) struct Workers {
+    guards: Vec<WorkerGuard>,
+}
+
+impl Workers {
+    fn make_writer<W: Write + Send + 'static>(
+        &mut self,
+        config: &NonBlockingConfig,
+        writer: W,
+    ) -> BoxMakeWriter {
+        if !config.enabled {
+            return BoxMakeWriter::new(std::sync::Mutex::new(writer));
+        }
+        let (writer, guard) = NonBlockingBuilder::default()
+            .buffered_lines_limit(config.buffered_lines_limit)
+            .lossy(config.lossy)
+            .thread_name("log-writer")
+            .finish(writer);
+        ERROR_COUNTERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(writer.error_counter());
+        self.guards.push(guard);
+        BoxMakeWriter::new(writer)
+    }
+}
+
+/// One log output, e.g.
+///
+/// ```toml
+/// [[log_config.sinks]]
+/// type = "stdout"
+/// level = "warn"
+/// ansi = true
+///
+/// [[log_config.sinks]]
+/// type = "file"
+/// level = "debug"
+/// format = "json"
+/// path = "logs"
+///
+/// [[log_config.sinks]]
+/// type = "syslog"
+/// level = "warn"
+/// syslog = { address = "tcp://10.0.0.1:601", facility = "daemon" }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    kind: SinkKind,
+    /// filter directives applied to this sink only, e.g. `warn` or `info,my_crate=debug`
+    level: Option<String>,
+    /// defaults to `LogConfig.format`
+    format: Option<LogFormat>,
+    ansi: bool,
+    /// log directory, required by `file` sinks
+    path: Option<String>,
+    rotation: RotationConfig,
+    /// used by `syslog` sinks
+    syslog: SyslogConfig,
+}
+
+impl SinkConfig {
+    pub(crate) fn stdout(level: &str) -> Self {
+        Self {
+            level: Some(level.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn file(path: &str, rotation: &RotationConfig) -> Self {
+        Self {
+            kind: SinkKind::File,
+            path: Some(path.to_owned()),
+            rotation: rotation.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn layer<S>(
+        &self,
+        name: &str,
+        default_format: LogFormat,
+        timer: LogTimer,
+        non_blocking: &NonBlockingConfig,
+        workers: &mut Workers,
+        redactor: Arc<Redactor>,
+    ) -> Result<Box<dyn Layer<S> + Send + Sync>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let format = self.format.unwrap_or(default_format);
+        let layer = match self.kind {
+            SinkKind::Stdout => {
+                let writer = workers.make_writer(non_blocking, std::io::stdout());
+                format::fmt_layer(name, format, timer, writer, self.ansi, redactor)
+            }
+            SinkKind::Stderr => {
+                let writer = workers.make_writer(non_blocking, std::io::stderr());
+                format::fmt_layer(name, format, timer, writer, self.ansi, redactor)
+            }
+            SinkKind::File => {
+                let path = self
+                    .path
+                    .as_ref()
+                    .ok_or_else(|| eyre!("file log sink requires a path"))?;
+                let writer = RollingFileWriter::new(path, &self.rotation, name, timer.timezone())?;
+                let writer = workers.make_writer(non_blocking, writer);
+                format::fmt_layer(name, format, timer, writer, self.ansi, redactor)
+            }
+            SinkKind::Syslog => {
+                let writer = self.syslog.writer()?;
+                let writer = workers.make_writer(non_blocking, writer);
+                SyslogLayer::new(name, &self.syslog, timer, writer, redactor).boxed()
+            }
+            #[cfg(target_os = "linux")]
+            SinkKind::Journald => {
+                let writer = workers.make_writer(non_blocking, JournaldWriter::default());
+                JournaldLayer::new(name, writer, redactor).boxed()
+            }
+        };
+
+        Ok(match &self.level {
+            Some(level) => {
+                let filter = EnvFilter::try_new(level)
+                    .map_err(|e| eyre!("invalid log sink level `{level}`: {e}"))?;
+                layer.with_filter(filter).boxed()
+            }
+            None => layer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::log::{subscriber, LogConfig};
+
+    fn file_sink(dir: &Path, level: &str) -> SinkConfig {
+        SinkConfig {
+            level: Some(level.to_owned()),
+            ..SinkConfig::file(&dir.display().to_string(), &Default::default())
+        }
+    }
+
+    fn read_logs(dir: &Path) -> String {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sinks_filter_by_their_level() {
+        let warn_dir = tempfile::tempdir().unwrap();
+        let debug_dir = tempfile::tempdir().unwrap();
+        let config = LogConfig {
+            filter: "debug".to_owned(),
+            sinks: vec![
+                file_sink(warn_dir.path(), "warn"),
+                file_sink(debug_dir.path(), "debug,noisy=off"),
+            ],
+            ..Default::default()
+        };
+        let (subscriber, _handle, guard) = subscriber("app", &config).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("debug event");
+            tracing::info!("info event");
+            tracing::warn!("warn event");
+            tracing::error!("error event");
+            tracing::warn!(target: "noisy", "noisy event");
+        });
+        drop(guard);
+
+        let warn_logs = read_logs(warn_dir.path());
+        assert_eq!(warn_logs.lines().count(), 3, "{warn_logs}");
+        for event in ["warn event", "error event", "noisy event"] {
+            assert!(warn_logs.contains(event), "{event} in {warn_logs}");
+        }
+
+        let debug_logs = read_logs(debug_dir.path());
+        assert_eq!(debug_logs.lines().count(), 4, "{debug_logs}");
+        for event in ["debug event", "info event", "warn event", "error event"] {
+            assert!(debug_logs.contains(event), "{event} in {debug_logs}");
+        }
+        assert!(!debug_logs.contains("noisy"), "{debug_logs}");
+    }
+}
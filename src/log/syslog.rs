@@ -0,0 +1,443 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+};
+
+use chrono::SecondsFormat;
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{writer::BoxMakeWriter, MakeWriter},
+    layer::Context,
+    Layer,
+};
+
+use super::{redact::EventFields, LogTimer, Redactor};
+
+/// SD-ID of the structured data element holding event fields, 32473 is the
+/// private enterprise number reserved for documentation.
+const SD_ID: &str = "fields@32473";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    #[default]
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Syslog severity of `level`.
+pub(crate) const fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    /// `udp://host:port`, `tcp://host:port` or `unix:///dev/log`
+    address: String,
+    facility: Facility,
+    /// defaults to the system host name
+    hostname: Option<String>,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            address: "udp://127.0.0.1:514".to_owned(),
+            facility: Default::default(),
+            hostname: Default::default(),
+        }
+    }
+}
+
+impl SyslogConfig {
+    pub(crate) fn writer(&self) -> Result<SyslogWriter> {
+        SyslogWriter::new(&self.address)
+    }
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(Vec<SocketAddr>, Option<TcpStream>),
+    #[cfg(unix)]
+    Unix(String, Option<UnixDatagram>),
+}
+
+/// Sends each write as one syslog message, framed by octet counting over TCP.
+///
+/// TCP and Unix socket connections are established on first use and
+/// re-established after a failed send.
+pub struct SyslogWriter {
+    transport: Transport,
+}
+
+impl SyslogWriter {
+    pub fn new(address: &str) -> Result<Self> {
+        let resolve = |addr: &str| {
+            addr.to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>())
+                .ok()
+                .filter(|addrs| !addrs.is_empty())
+                .ok_or_else(|| eyre!("invalid syslog address `{address}`"))
+        };
+        let transport = if let Some(addr) = address.strip_prefix("udp://") {
+            let addrs = resolve(addr)?;
+            let local: SocketAddr = if addrs[0].is_ipv4() {
+                ([0, 0, 0, 0], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let socket =
+                UdpSocket::bind(local).map_err(|e| eyre!("bind syslog udp socket failed: {e}"))?;
+            socket
+                .connect(addrs.as_slice())
+                .map_err(|e| eyre!("connect syslog `{address}` failed: {e}"))?;
+            Transport::Udp(socket)
+        } else if let Some(addr) = address.strip_prefix("tcp://") {
+            Transport::Tcp(resolve(addr)?, None)
+        } else {
+            #[cfg(unix)]
+            if let Some(path) = address.strip_prefix("unix://") {
+                return Ok(Self {
+                    transport: Transport::Unix(path.to_owned(), None),
+                });
+            }
+            return Err(eyre!("invalid syslog address `{address}`"));
+        };
+        Ok(Self { transport })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Udp(socket) => socket.send(message).map(|_| ()),
+            Transport::Tcp(addrs, stream) => {
+                let stream = match stream {
+                    Some(stream) => stream,
+                    None => stream.insert(TcpStream::connect(addrs.as_slice())?),
+                };
+                write!(stream, "{} ", message.len())?;
+                stream.write_all(message)
+            }
+            #[cfg(unix)]
+            Transport::Unix(path, socket) => {
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => {
+                        let socket = socket.insert(UnixDatagram::unbound()?);
+                        socket.connect(&*path)?;
+                        socket
+                    }
+                };
+                socket.send(message).map(|_| ())
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        match &mut self.transport {
+            Transport::Udp(_) => {}
+            Transport::Tcp(_, stream) => *stream = None,
+            #[cfg(unix)]
+            Transport::Unix(_, socket) => *socket = None,
+        }
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // retry once on a fresh connection, e.g. after the server restarted
+        if self.send(buf).is_err() {
+            self.disconnect();
+            if let Err(e) = self.send(buf) {
+                self.disconnect();
+                return Err(e);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats events as RFC 5424 messages, event fields go into structured data.
+pub(crate) struct SyslogLayer {
+    writer: BoxMakeWriter,
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    timer: LogTimer,
+    redactor: Arc<Redactor>,
+}
+
+impl SyslogLayer {
+    pub(crate) fn new(
+        name: &str,
+        config: &SyslogConfig,
+        timer: LogTimer,
+        writer: BoxMakeWriter,
+        redactor: Arc<Redactor>,
+    ) -> Self {
+        let hostname = config.hostname.clone().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|hostname| hostname.trim().to_owned())
+                .unwrap_or_default()
+        });
+        Self {
+            writer,
+            facility: config.facility,
+            hostname: header_field(&hostname, 255),
+            app_name: header_field(name, 48),
+            timer,
+            redactor,
+        }
+    }
+
+    fn format(&self, event: &Event<'_>) -> String {
+        let metadata = event.metadata();
+        let fields = EventFields::new(event, &self.redactor);
+        let priority = self.facility as u8 * 8 + severity(metadata.level());
+        let timestamp = self
+            .timer
+            .timezone()
+            .now()
+            .to_rfc3339_opts(SecondsFormat::Micros, true);
+
+        let mut message = format!(
+            "<{priority}>1 {timestamp} {} {} {} - [{SD_ID} target=\"{}\"",
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            param_value(metadata.target()),
+        );
+        for (name, value) in &fields.fields {
+            let _ = write!(message, " {}=\"{}\"", param_name(name), param_value(value));
+        }
+        message.push(']');
+        if !fields.message.is_empty() {
+            message.push(' ');
+            message.push_str(&fields.message);
+        }
+        message
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let message = self.format(event);
+        let mut writer = self.writer.make_writer_for(event.metadata());
+        if let Err(e) = writer.write_all(message.as_bytes()) {
+            eprintln!("write syslog failed: {e}");
+        }
+    }
+}
+
+/// Printable ASCII without spaces, `-` when empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        "-".to_owned()
+    } else {
+        value
+    }
+}
+
+fn param_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '=' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect()
+}
+
+fn param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::net::UnixDatagram as UnixReceiver;
+    use std::{io::Read, net::TcpListener, sync::Mutex};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::log::LogConfig;
+
+    fn layer(address: &str, facility: Facility) -> SyslogLayer {
+        let config = SyslogConfig {
+            address: address.to_owned(),
+            facility,
+            hostname: Some("host".to_owned()),
+        };
+        SyslogLayer::new(
+            "app",
+            &config,
+            LogConfig::default().timer().unwrap(),
+            BoxMakeWriter::new(Mutex::new(config.writer().unwrap())),
+            Default::default(),
+        )
+    }
+
+    /// Log `count` warnings with a field which needs escaping through `layer`.
+    fn log(layer: SyslogLayer, count: usize) {
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..count {
+                tracing::warn!(target: "syslog", user = r#"a"b]c\d"#, "hello");
+            }
+        });
+    }
+
+    /// Check the RFC 5424 header and return the structured data and message.
+    fn parse(message: &str, priority: u8) -> &str {
+        let mut parts = message.splitn(7, ' ');
+        assert_eq!(parts.next(), Some(format!("<{priority}>1").as_str()));
+        chrono::DateTime::parse_from_rfc3339(parts.next().unwrap()).unwrap();
+        assert_eq!(parts.next(), Some("host"));
+        assert_eq!(parts.next(), Some("app"));
+        assert_eq!(parts.next(), Some(std::process::id().to_string().as_str()));
+        assert_eq!(parts.next(), Some("-"));
+        parts.next().unwrap()
+    }
+
+    const WARNING: &str = r#"[fields@32473 target="syslog" user="a\"b\]c\\d"] hello"#;
+
+    #[test]
+    fn udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = format!("udp://{}", receiver.local_addr().unwrap());
+        log(layer(&address, Facility::Local0), 1);
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert_eq!(parse(message, 16 * 8 + 4), WARNING);
+    }
+
+    #[test]
+    fn tcp_octet_counting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        // both messages go over one connection, closed with the subscriber
+        log(layer(&address, Facility::User), 2);
+        let mut received = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+
+        let mut rest = received.as_str();
+        for _ in 0..2 {
+            let (len, frame) = rest.split_once(' ').unwrap();
+            let (message, next) = frame.split_at(len.parse().unwrap());
+            assert_eq!(parse(message, 8 + 4), WARNING);
+            rest = next;
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix() {
+        let path = std::env::temp_dir().join(format!("syslog-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixReceiver::bind(&path).unwrap();
+        log(
+            layer(&format!("unix://{}", path.display()), Facility::Daemon),
+            1,
+        );
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert_eq!(parse(message, 3 * 8 + 4), WARNING);
+    }
+
+    #[test]
+    fn priority() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = format!("udp://{}", receiver.local_addr().unwrap());
+        let subscriber = tracing_subscriber::registry().with(layer(&address, Facility::Local7));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("error");
+            tracing::info!("info");
+            tracing::debug!("debug");
+            tracing::trace!("trace");
+        });
+        let mut buf = [0; 1024];
+        for severity in [3, 6, 7, 7] {
+            let len = receiver.recv(&mut buf).unwrap();
+            let message = std::str::from_utf8(&buf[..len]).unwrap();
+            assert!(message.starts_with(&format!("<{}>1 ", 23 * 8 + severity)));
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(param_value(r#"a"b\c]d[e"#), r#"a\"b\\c\]d[e"#);
+        assert_eq!(param_name("a b=c]d\"e"), "a_b_c_d_e");
+        assert_eq!(header_field("", 48), "-");
+        assert_eq!(header_field("my app", 4), "myap");
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{format::StrftimeItems, DateTime, FixedOffset, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use color_eyre::{
+    eyre::{eyre, Error},
+    Result,
+};
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+/// Timezone of log timestamps and rotated file names:
+/// `utc`, `local`, a fixed offset like `+08:00` or an IANA name like `Asia/Shanghai`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimezone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl FromStr for LogTimezone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "utc" | "z" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            _ => s
+                .parse::<FixedOffset>()
+                .map(Self::Fixed)
+                .or_else(|_| s.parse::<Tz>().map(Self::Named))
+                .map_err(|_| eyre!("invalid log timezone `{s}`")),
+        }
+    }
+}
+
+impl LogTimezone {
+    pub fn now(&self) -> DateTime<FixedOffset> {
+        let now = Utc::now();
+        match self {
+            Self::Utc => now.fixed_offset(),
+            Self::Local => now.with_timezone(&chrono::Local).fixed_offset(),
+            Self::Fixed(offset) => now.with_timezone(offset),
+            Self::Named(tz) => now.with_timezone(tz).fixed_offset(),
+        }
+    }
+}
+
+/// `rfc3339`, `rfc2822` or a custom `strftime` format like `%Y-%m-%d %H:%M:%S%.3f`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampFormat {
+    Rfc3339,
+    Rfc2822,
+    Custom(String),
+}
+
+impl FromStr for TimestampFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc3339" => Ok(Self::Rfc3339),
+            "rfc2822" => Ok(Self::Rfc2822),
+            _ => StrftimeItems::new(s)
+                .parse()
+                .map(|_| Self::Custom(s.to_owned()))
+                .map_err(|e| eyre!("invalid log timestamp format `{s}`: {e}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogTimer {
+    timezone: LogTimezone,
+    format: TimestampFormat,
+}
+
+impl LogTimer {
+    pub const fn new(timezone: LogTimezone, format: TimestampFormat) -> Self {
+        Self { timezone, format }
+    }
+
+    pub const fn timezone(&self) -> LogTimezone {
+        self.timezone
+    }
+}
+
+impl FormatTime for LogTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        let now = self.timezone.now();
+        match &self.format {
+            TimestampFormat::Rfc3339 => {
+                write!(w, "{}", now.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            TimestampFormat::Rfc2822 => write!(w, "{}", now.to_rfc2822()),
+            TimestampFormat::Custom(format) => write!(w, "{}", now.format(format)),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, sync::OnceLock, time::Instant};
+
+use color_eyre::{eyre::eyre, Result};
+#[cfg(feature = "log")]
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntCounter,
+};
+use prometheus::{
+    histogram_opts, opts, Encoder, HistogramVec, IntCounterVec, Registry, TextEncoder,
+};
+
+pub use prometheus;
+
+/// Registry served on `/metrics` by `restful::http_serve`, metrics registered
+/// with `prometheus::register_*!` macros end up here as well.
+pub fn registry() -> &'static Registry {
+    prometheus::default_registry()
+}
+
+/// Encode all metrics of [`registry`] in the Prometheus text format.
+pub fn gather() -> Result<String> {
+    // register the metrics of this crate even if none was recorded yet
+    metrics();
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&registry().gather(), &mut buffer)
+        .map_err(|e| eyre!("encode metrics failed: {e}"))?;
+    String::from_utf8(buffer).map_err(|e| eyre!("encode metrics failed: {e}"))
+}
+
+struct Metrics {
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    operation_duration: HistogramVec,
+    operation_errors: IntCounterVec,
+    reconnects: IntCounterVec,
+    heartbeats: IntCounterVec,
+    #[cfg(feature = "log")]
+    log_dropped_lines: LogDroppedLines,
+}
+
+/// Reports [`crate::log::dropped_lines`] whenever gathered.
+#[cfg(feature = "log")]
+#[derive(Clone)]
+struct LogDroppedLines(IntCounter);
+
+#[cfg(feature = "log")]
+impl Collector for LogDroppedLines {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let dropped = crate::log::dropped_lines() as u64;
+        let counted = self.0.get();
+        if dropped > counted {
+            self.0.inc_by(dropped - counted);
+        }
+        self.0.collect()
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| {
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                opts!("http_requests_total", "HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                histogram_opts!(
+                    "http_request_duration_seconds",
+                    "HTTP request handling latency"
+                ),
+                &["method", "route"],
+            )
+            .unwrap(),
+            operation_duration: HistogramVec::new(
+                histogram_opts!(
+                    "backend_operation_duration_seconds",
+                    "Redis and etcd operation latency"
+                ),
+                &["backend", "operation"],
+            )
+            .unwrap(),
+            operation_errors: IntCounterVec::new(
+                opts!(
+                    "backend_operation_errors_total",
+                    "Redis and etcd operations failed"
+                ),
+                &["backend", "operation"],
+            )
+            .unwrap(),
+            reconnects: IntCounterVec::new(
+                opts!("backend_reconnects_total", "Redis and etcd reconnections"),
+                &["backend"],
+            )
+            .unwrap(),
+            heartbeats: IntCounterVec::new(
+                opts!(
+                    "service_register_heartbeats_total",
+                    "Service registration renewals"
+                ),
+                &["backend", "result"],
+            )
+            .unwrap(),
+            #[cfg(feature = "log")]
+            log_dropped_lines: LogDroppedLines(
+                IntCounter::new(
+                    "log_dropped_lines_total",
+                    "Log lines dropped because a sink's buffer was full",
+                )
+                .unwrap(),
+            ),
+        };
+        let registry = registry();
+        #[allow(unused_mut)]
+        let mut collectors = vec![
+            Box::new(metrics.http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.operation_duration.clone()),
+            Box::new(metrics.operation_errors.clone()),
+            Box::new(metrics.reconnects.clone()),
+            Box::new(metrics.heartbeats.clone()),
+        ];
+        #[cfg(feature = "log")]
+        collectors.push(Box::new(metrics.log_dropped_lines.clone()));
+        for collector in collectors {
+            if let Err(e) = registry.register(collector) {
+                tracing::warn!("register metrics failed: {e}");
+            }
+        }
+        metrics
+    })
+}
+
+/// Record a handled HTTP request, `route` is the matched route template.
+pub fn observe_http(method: &str, route: &str, status: u16, started: Instant) {
+    let metrics = metrics();
+    metrics
+        .http_requests
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&[method, route])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Record latency and failure of a Redis or etcd operation.
+pub async fn observe<T, E>(
+    backend: &str,
+    operation: &str,
+    future: impl Future<Output = std::result::Result<T, E>>,
+) -> std::result::Result<T, E> {
+    let metrics = metrics();
+    let started = Instant::now();
+    let result = future.await;
+    metrics
+        .operation_duration
+        .with_label_values(&[backend, operation])
+        .observe(started.elapsed().as_secs_f64());
+    if result.is_err() {
+        metrics
+            .operation_errors
+            .with_label_values(&[backend, operation])
+            .inc();
+    }
+    result
+}
+
+/// Count a reconnection to `backend`.
+pub fn reconnect(backend: &str) {
+    metrics().reconnects.with_label_values(&[backend]).inc();
+}
+
+/// Count a service registration renewal on `backend`.
+pub fn heartbeat(backend: &str, ok: bool) {
+    metrics()
+        .heartbeats
+        .with_label_values(&[backend, if ok { "ok" } else { "error" }])
+        .inc();
+}
+
+/// Operations of `backend` recorded as `operation` so far and how many failed.
+#[cfg(all(test, feature = "redis"))]
+pub(crate) fn operations(backend: &str, operation: &str) -> (u64, u64) {
+    let metrics = metrics();
+    (
+        metrics
+            .operation_duration
+            .with_label_values(&[backend, operation])
+            .get_sample_count(),
+        metrics
+            .operation_errors
+            .with_label_values(&[backend, operation])
+            .get(),
+    )
+}
@@ -1,87 +1,160 @@
+mod client;
+mod connection;
+mod discovery;
+#[cfg(test)]
+mod fake;
+mod lock;
+mod typed;
+
+use std::{sync::Arc, time::Duration};
+
 use color_eyre::{eyre::eyre, Result};
 pub use redis::*;
 
 use serde::{Deserialize, Serialize};
 
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use crate::service_register::{ServiceRegister, ServiceRegisterConfig};
+use crate::{
+    metrics,
+    service_discovery::ServiceInstance,
+    service_register::{Registration, ServiceRegister, ServiceRegisterConfig},
+};
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "redis-cluster")] {
-        pub use redis::{cluster::ClusterClient as RedisClient, cluster_async::ClusterConnection as RedisConnection};
-    } else {
-        pub use redis::{aio::MultiplexedConnection as RedisConnection, Client as RedisClient};
-    }
-}
+#[cfg(feature = "redis-tls")]
+pub use client::RedisTlsConfig;
+pub use client::{RedisClient, RedisConnection, RedisMode, SentinelConfig};
+pub use connection::{
+    ConnectionPool, ConnectionState, ManagedConnection, PooledConnection, ReconnectConfig,
+};
+pub use lock::{RedisLock, RedisLockGuard};
 
 #[derive(Clone)]
 pub struct Redis {
     client: RedisClient,
-    connection: RedisConnection,
+    connection: ManagedConnection,
+    read_connection: ManagedConnection,
+    pool: Arc<ConnectionPool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct RedisConfig {
+    pub mode: RedisMode,
+    /// server, cluster node or sentinel URLs depending on `mode`
     pub endpoints: Vec<String>,
+    /// ACL user, overrides the one in `endpoints`
+    pub username: Option<String>,
+    /// overrides the one in `endpoints`
+    pub password: Option<String>,
+    /// database index, overrides the one in `endpoints`
+    pub db: Option<i64>,
+    #[cfg(feature = "redis-tls")]
+    pub tls: Option<RedisTlsConfig>,
+    /// connect timeout in milliseconds, 0 disables it
+    pub connect_timeout: u64,
+    /// command response timeout in milliseconds, 0 disables it,
+    /// never applied to [`Redis::blocking_conn`]
+    pub response_timeout: u64,
+    /// set with `CLIENT SETNAME` on every connection, not applied in `cluster` mode
+    pub client_name: Option<String>,
+    /// used in `sentinel` mode
+    pub sentinel: SentinelConfig,
+    pub reconnect: ReconnectConfig,
+    /// max dedicated connections for blocking commands, see [`Redis::blocking_conn`]
+    pub pool_size: usize,
 }
 
 impl Default for RedisConfig {
     fn default() -> Self {
         Self {
+            mode: Default::default(),
             endpoints: vec!["redis://127.0.0.1/".to_owned()],
+            username: Default::default(),
+            password: Default::default(),
+            db: Default::default(),
+            #[cfg(feature = "redis-tls")]
+            tls: Default::default(),
+            connect_timeout: 5000,
+            response_timeout: 5000,
+            client_name: Default::default(),
+            sentinel: Default::default(),
+            reconnect: Default::default(),
+            pool_size: 8,
         }
     }
 }
 
+impl RedisConfig {
+    fn connect_timeout(&self) -> Option<Duration> {
+        (self.connect_timeout > 0).then(|| Duration::from_millis(self.connect_timeout))
+    }
+
+    fn response_timeout(&self) -> Option<Duration> {
+        (self.response_timeout > 0).then(|| Duration::from_millis(self.response_timeout))
+    }
+}
+
 impl Redis {
     pub async fn new(config: &RedisConfig) -> Result<Self> {
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "redis-cluster")] {
-                let client = RedisClient::new(config.endpoints.clone())
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+        let client = RedisClient::new(config)?;
+        let connection = ManagedConnection::new(client.clone(), config.reconnect).await?;
 
-                let connection = client
-                    .get_async_connection()
-                    .await
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+        let read_connection =
+            if config.mode == RedisMode::Sentinel && config.sentinel.read_from_replicas {
+                let replica = RedisClient::replica(config)?;
+                match ManagedConnection::new(replica, config.reconnect).await {
+                    Ok(read_connection) => read_connection,
+                    Err(e) => {
+                        warn!("redis replica unavailable, reading from master: {e}");
+                        connection.clone()
+                    }
+                }
             } else {
-                let client = Client::open(config.endpoints[0].clone())
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                connection.clone()
+            };
 
-                let connection = client
-                    .get_multiplexed_async_connection()
-                    .await
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
-            }
-        }
-        Ok(Self { client, connection })
+        // blocking commands may wait far longer than any response timeout
+        let pool_client = RedisClient::new(&RedisConfig {
+            response_timeout: 0,
+            ..config.clone()
+        })?;
+        let pool = Arc::new(ConnectionPool::new(pool_client, config.pool_size));
+        Ok(Self {
+            client,
+            connection,
+            read_connection,
+            pool,
+        })
     }
 
     pub fn client(&self) -> RedisClient {
         self.client.to_owned()
     }
 
-    pub fn conn(&self) -> RedisConnection {
+    /// The shared connection, reconnected automatically when it fails.
+    pub fn conn(&self) -> ManagedConnection {
         self.connection.to_owned()
     }
 
+    /// Connection for reads which tolerate replication lag, served from a
+    /// replica when `sentinel.read_from_replicas` is set, otherwise the same as [`Self::conn`].
+    pub fn read_conn(&self) -> ManagedConnection {
+        self.read_connection.to_owned()
+    }
+
+    /// A dedicated connection for blocking commands like `BLPOP`, returned to
+    /// the pool when dropped.
+    pub async fn blocking_conn(&self) -> Result<PooledConnection> {
+        self.pool.get().await
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    #[deprecated(note = "connections are re-established automatically")]
     pub async fn keep_alive(&mut self) -> Result<()> {
-        cfg_if::cfg_if! {
-            if #[cfg(not(feature = "redis-cluster"))] {
-                if !self.client.check_connection() {
-                    if let Ok(new_conn) = self
-                        .client
-                        .get_multiplexed_async_connection()
-                        .await
-                        .map_err(|e| eyre!("redis connect failed: {e}"))
-                    {
-                        self.connection = new_conn;
-                    }
-                }
-            }
-        }
         Ok(())
     }
 
@@ -89,7 +162,7 @@ impl Redis {
         &self,
         service_name: &str,
         config: ServiceRegisterConfig,
-    ) -> Result<()> {
+    ) -> Result<Registration> {
         self.keep_service_register(service_name, config).await
     }
 }
@@ -99,33 +172,55 @@ impl ServiceRegister for Redis {
         &self,
         service_name: &str,
         config: ServiceRegisterConfig,
-    ) -> Result<()> {
-        debug!("keep_service_register: {config:#?}");
+    ) -> Result<Registration> {
+        let instance_id = config.instance_id(service_name);
+        debug!("keep_service_register: {instance_id} {config:#?}");
         let mut keep_alive_interval =
             tokio::time::interval(tokio::time::Duration::from_secs((config.ttl / 2) as u64));
 
         let redis = self.clone();
         let service_name = service_name.to_owned();
-        tokio::spawn(async move {
+        let server_key = format!(
+            "traefik/http/services/{}/loadbalancer/servers/{}/url",
+            service_name, instance_id
+        );
+        let (stop_tx, mut stop) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move {
             loop {
-                keep_alive_interval.tick().await;
+                tokio::select! {
+                    _ = keep_alive_interval.tick() => {}
+                    Ok(_) = stop.wait_for(|stop| *stop) => break,
+                }
                 let tags = config.tags.clone();
                 let service_name = service_name.clone();
 
+                let mut ok = true;
+
                 match redis
                     .conn()
-                    .set_ex(
-                        format!(
-                            "traefik/http/services/{}/loadbalancer/servers/{}/url",
-                            service_name, service_name
-                        ),
-                        config.url.clone(),
-                        config.ttl as u64,
-                    )
+                    .set_ex(server_key.clone(), config.url.clone(), config.ttl as u64)
                     .await
                 {
                     Ok(()) => {}
-                    Err(e) => error!("keep_service_register failed: {:?}", e),
+                    Err(e) => {
+                        error!("keep_service_register failed: {:?}", e);
+                        ok = false;
+                    }
+                }
+                let instance = ServiceInstance {
+                    id: instance_id.clone(),
+                    url: config.url.clone(),
+                    tags: tags.clone(),
+                };
+                match redis
+                    .register_instance(&service_name, instance, config.ttl as u64)
+                    .await
+                {
+                    Ok(()) => {}
+                    Err(e) => {
+                        error!("keep_service_register failed: {:?}", e);
+                        ok = false;
+                    }
                 }
                 match redis
                     .conn()
@@ -137,17 +232,100 @@ impl ServiceRegister for Redis {
                     .await
                 {
                     Ok(()) => {}
-                    Err(e) => error!("keep_service_register failed: {:?}", e),
+                    Err(e) => {
+                        error!("keep_service_register failed: {:?}", e);
+                        ok = false;
+                    }
                 }
                 for tag in tags {
                     let (key, value) = tag.split_once('=').unwrap_or_default();
                     match redis.conn().set_ex(key, value, config.ttl as u64).await {
                         Ok(()) => {}
-                        Err(e) => error!("keep_service_register failed: {:?}", e),
+                        Err(e) => {
+                            error!("keep_service_register failed: {:?}", e);
+                            ok = false;
+                        }
                     }
                 }
+                metrics::heartbeat("redis", ok);
             }
+            debug!("deregister: {instance_id}");
+            // the keys shared by all replicas expire after the last one is gone
+            redis
+                .conn()
+                .del::<_, ()>(server_key)
+                .await
+                .map_err(|e| eyre!("redis del failed: {e}"))?;
+            redis.deregister_instance(&service_name, &instance_id).await
         });
-        Ok(())
+        Ok(Registration::new(async move {
+            stop_tx.send_replace(true);
+            task.await
+                .map_err(|e| eyre!("keep_service_register task failed: {e}"))?
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        redis::fake::FakeRedis, service_discovery::ServiceDiscovery,
+        service_register::random_instance_id,
+    };
+
+    const SERVERS: &str = "traefik/http/services/service/loadbalancer/servers/";
+
+    async fn wait_for_servers(fake: &FakeRedis, count: usize) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let servers = fake.keys(SERVERS);
+                if servers.len() == count {
+                    return servers;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replicas_register_separate_servers() {
+        let (fake, redis) = FakeRedis::redis().await;
+        // what replicas without a host name fall back to
+        let ids = [random_instance_id("service"), random_instance_id("service")];
+        let mut registrations = Vec::new();
+        for id in &ids {
+            let config = ServiceRegisterConfig {
+                url: format!("http://{id}:8080"),
+                instance_id: Some(id.clone()),
+                ..Default::default()
+            };
+            registrations.push(
+                redis
+                    .keep_service_register("service", config)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let mut expected = ids
+            .iter()
+            .map(|id| format!("{SERVERS}{id}/url"))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(wait_for_servers(&fake, 2).await, expected);
+        assert!(fake.exists("traefik/http/routers/service/service"));
+        let instances = redis.resolve("service").await.unwrap();
+        assert_eq!(instances.len(), 2);
+
+        registrations.remove(0).deregister().await.unwrap();
+        assert_eq!(
+            wait_for_servers(&fake, 1).await,
+            [format!("{SERVERS}{}/url", ids[1])]
+        );
+        let instances = redis.resolve("service").await.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].id, ids[1]);
     }
 }
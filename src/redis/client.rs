@@ -0,0 +1,350 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType},
+    AsyncConnectionConfig, Client, Cmd, ConnectionAddr, ConnectionInfo, IntoConnectionInfo,
+    Pipeline, RedisConnectionInfo, RedisFuture, RedisResult, TlsMode, Value,
+};
+#[cfg(feature = "redis-cluster")]
+use redis::{
+    cluster::{ClusterClient, ClusterClientBuilder},
+    cluster_async::ClusterConnection,
+};
+#[cfg(feature = "redis-tls")]
+use redis::{ClientTlsConfig, TlsCertificates};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::RedisConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisMode {
+    /// `endpoints` holds a single server URL
+    Standalone,
+    /// `endpoints` holds cluster node URLs, requires the `redis-cluster` feature
+    Cluster,
+    /// `endpoints` holds sentinel URLs
+    Sentinel,
+}
+
+/// `cluster` with the `redis-cluster` feature, which always connected to a
+/// cluster before the mode was configurable, `standalone` otherwise.
+impl Default for RedisMode {
+    fn default() -> Self {
+        if cfg!(feature = "redis-cluster") {
+            Self::Cluster
+        } else {
+            Self::Standalone
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SentinelConfig {
+    /// name of the master monitored by the sentinels
+    pub master_name: String,
+    /// serve [`crate::redis::Redis::read_conn`] from a replica
+    pub read_from_replicas: bool,
+}
+
+impl Default for SentinelConfig {
+    fn default() -> Self {
+        Self {
+            master_name: "mymaster".to_owned(),
+            read_from_replicas: false,
+        }
+    }
+}
+
+/// TLS for all `endpoints`, `rediss://` URLs enable it as well.
+#[cfg(feature = "redis-tls")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedisTlsConfig {
+    /// PEM CA certificate file, the system roots are used when unset
+    pub ca_cert: Option<String>,
+    /// PEM client certificate file for mutual TLS
+    pub client_cert: Option<String>,
+    /// PEM client key file for mutual TLS
+    pub client_key: Option<String>,
+    /// skip verification of the server certificate
+    pub insecure: bool,
+}
+
+#[cfg(feature = "redis-tls")]
+impl RedisTlsConfig {
+    fn certificates(&self) -> Result<TlsCertificates> {
+        let read = |path: &String| {
+            std::fs::read(path).map_err(|e| eyre!("read redis tls file `{path}` failed: {e}"))
+        };
+        let client_tls = match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => Some(ClientTlsConfig {
+                client_cert: read(client_cert)?,
+                client_key: read(client_key)?,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(eyre!(
+                    "redis tls client_cert and client_key must be set together"
+                ))
+            }
+        };
+        Ok(TlsCertificates {
+            client_tls,
+            root_cert: self.ca_cert.as_ref().map(read).transpose()?,
+        })
+    }
+}
+
+impl RedisConfig {
+    const fn tls_mode(&self) -> Option<TlsMode> {
+        #[cfg(feature = "redis-tls")]
+        if let Some(tls) = &self.tls {
+            return Some(if tls.insecure {
+                TlsMode::Insecure
+            } else {
+                TlsMode::Secure
+            });
+        }
+        None
+    }
+
+    /// Connection info of the data nodes in `endpoints` with auth, database and TLS applied.
+    fn connection_infos(&self) -> Result<Vec<ConnectionInfo>> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| {
+                let mut info = endpoint
+                    .as_str()
+                    .into_connection_info()
+                    .map_err(|e| eyre!("invalid redis endpoint `{endpoint}`: {e}"))?;
+                self.apply_auth(&mut info.redis);
+                if let (Some(tls_mode), ConnectionAddr::Tcp(host, port)) =
+                    (self.tls_mode(), &info.addr)
+                {
+                    info.addr = ConnectionAddr::TcpTls {
+                        host: host.clone(),
+                        port: *port,
+                        insecure: tls_mode == TlsMode::Insecure,
+                        tls_params: None,
+                    };
+                }
+                Ok(info)
+            })
+            .collect()
+    }
+
+    fn apply_auth(&self, info: &mut RedisConnectionInfo) {
+        if self.username.is_some() {
+            info.username.clone_from(&self.username);
+        }
+        if self.password.is_some() {
+            info.password.clone_from(&self.password);
+        }
+        if let Some(db) = self.db {
+            info.db = db;
+        }
+    }
+
+    fn sentinel_client(&self, server_type: SentinelServerType) -> Result<ClientKind> {
+        let mut redis_connection_info = RedisConnectionInfo::default();
+        self.apply_auth(&mut redis_connection_info);
+        let node_connection_info = SentinelNodeConnectionInfo {
+            tls_mode: self.tls_mode(),
+            redis_connection_info: Some(redis_connection_info),
+        };
+        let client = SentinelClient::build(
+            self.endpoints.clone(),
+            self.sentinel.master_name.clone(),
+            Some(node_connection_info),
+            server_type,
+        )
+        .map_err(|e| eyre!("redis sentinel connect failed: {e}"))?;
+        Ok(ClientKind::Sentinel(Arc::new(Mutex::new(client))))
+    }
+}
+
+#[derive(Clone)]
+enum ClientKind {
+    Standalone(Client),
+    #[cfg(feature = "redis-cluster")]
+    Cluster(ClusterClient),
+    /// asks the sentinels for the current master or a replica on every connect
+    Sentinel(Arc<Mutex<SentinelClient>>),
+}
+
+/// Client of the configured [`RedisMode`].
+#[derive(Clone)]
+pub struct RedisClient {
+    kind: ClientKind,
+    connect_timeout: Option<Duration>,
+    response_timeout: Option<Duration>,
+    client_name: Option<String>,
+}
+
+impl RedisClient {
+    /// Client of the server, cluster or sentinel master.
+    pub fn new(config: &RedisConfig) -> Result<Self> {
+        let kind = match config.mode {
+            RedisMode::Standalone => {
+                let infos = config.connection_infos()?;
+                let [info] = <[ConnectionInfo; 1]>::try_from(infos).map_err(|infos| {
+                    eyre!(
+                        "redis standalone mode takes exactly one endpoint, got {}, \
+                         use cluster or sentinel mode for multiple endpoints",
+                        infos.len()
+                    )
+                })?;
+                #[cfg(feature = "redis-tls")]
+                if let Some(tls) = &config.tls {
+                    let client = Client::build_with_tls(info, tls.certificates()?)
+                        .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                    return Ok(Self::with_kind(config, ClientKind::Standalone(client)));
+                }
+                ClientKind::Standalone(
+                    Client::open(info).map_err(|e| eyre!("redis connect failed: {e}"))?,
+                )
+            }
+            #[cfg(feature = "redis-cluster")]
+            RedisMode::Cluster => {
+                let mut builder = ClusterClientBuilder::new(config.connection_infos()?);
+                if let Some(timeout) = config.connect_timeout() {
+                    builder = builder.connection_timeout(timeout);
+                }
+                if let Some(timeout) = config.response_timeout() {
+                    builder = builder.response_timeout(timeout);
+                }
+                #[cfg(feature = "redis-tls")]
+                if let (Some(tls), Some(tls_mode)) = (&config.tls, config.tls_mode()) {
+                    builder = builder.tls(tls_mode).certs(tls.certificates()?);
+                }
+                ClientKind::Cluster(
+                    builder
+                        .build()
+                        .map_err(|e| eyre!("redis connect failed: {e}"))?,
+                )
+            }
+            #[cfg(not(feature = "redis-cluster"))]
+            RedisMode::Cluster => {
+                return Err(eyre!(
+                    "redis cluster mode requires the `redis-cluster` feature"
+                ))
+            }
+            RedisMode::Sentinel => config.sentinel_client(SentinelServerType::Master)?,
+        };
+        Ok(Self::with_kind(config, kind))
+    }
+
+    /// Client of a replica of the sentinel master.
+    pub fn replica(config: &RedisConfig) -> Result<Self> {
+        if config.mode != RedisMode::Sentinel {
+            return Err(eyre!("redis replicas are only discovered in sentinel mode"));
+        }
+        let kind = config.sentinel_client(SentinelServerType::Replica)?;
+        Ok(Self::with_kind(config, kind))
+    }
+
+    fn with_kind(config: &RedisConfig, kind: ClientKind) -> Self {
+        Self {
+            kind,
+            connect_timeout: config.connect_timeout(),
+            response_timeout: config.response_timeout(),
+            client_name: config.client_name.clone(),
+        }
+    }
+
+    pub async fn get_connection(&self) -> RedisResult<RedisConnection> {
+        let mut config = AsyncConnectionConfig::new();
+        if let Some(timeout) = self.connect_timeout {
+            config = config.set_connection_timeout(timeout);
+        }
+        if let Some(timeout) = self.response_timeout {
+            config = config.set_response_timeout(timeout);
+        }
+        let connection = match &self.kind {
+            ClientKind::Standalone(client) => client
+                .get_multiplexed_async_connection_with_config(&config)
+                .await
+                .map(RedisConnection::Standalone)?,
+            // the cluster client names no connections, it opens them to every node
+            #[cfg(feature = "redis-cluster")]
+            ClientKind::Cluster(client) => {
+                return client
+                    .get_async_connection()
+                    .await
+                    .map(RedisConnection::Cluster)
+            }
+            ClientKind::Sentinel(client) => client
+                .lock()
+                .await
+                .get_async_connection_with_config(&config)
+                .await
+                .map(RedisConnection::Standalone)?,
+        };
+        let mut connection = connection;
+        if let Some(client_name) = &self.client_name {
+            redis::cmd("CLIENT")
+                .arg("SETNAME")
+                .arg(client_name)
+                .query_async::<()>(&mut connection)
+                .await?;
+        }
+        Ok(connection)
+    }
+}
+
+#[derive(Clone)]
+pub enum RedisConnection {
+    Standalone(MultiplexedConnection),
+    #[cfg(feature = "redis-cluster")]
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Standalone(connection) => connection.req_packed_command(cmd),
+            #[cfg(feature = "redis-cluster")]
+            Self::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Standalone(connection) => connection.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "redis-cluster")]
+            Self::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Standalone(connection) => connection.get_db(),
+            #[cfg(feature = "redis-cluster")]
+            Self::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
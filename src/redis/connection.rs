@@ -0,0 +1,425 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use redis::{aio::ConnectionLike, Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use super::{RedisClient, RedisConnection};
+use crate::metrics;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// delay in milliseconds before the first reconnection attempt
+    initial_delay: u64,
+    /// delays double after every failed attempt up to this many milliseconds,
+    /// each one randomized between half and full length
+    max_delay: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: 100,
+            max_delay: 30000,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << attempt.min(32))
+            .min(self.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() % (delay / 2 + 1);
+        Duration::from_millis(delay - jitter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// the connection was lost, commands fail until it is re-established
+    Reconnecting,
+}
+
+struct Shared {
+    client: RedisClient,
+    config: ReconnectConfig,
+    connection: RwLock<Option<RedisConnection>>,
+    reconnecting: AtomicBool,
+    state: watch::Sender<ConnectionState>,
+}
+
+/// A shared connection which is re-established in the background once it fails.
+///
+/// Commands issued while reconnecting fail immediately instead of waiting, they
+/// are never retried since they may not be idempotent.
+///
+/// Every command is recorded in the operation metrics under its name, error
+/// replies count as failures.
+#[derive(Clone)]
+pub struct ManagedConnection {
+    shared: Arc<Shared>,
+}
+
+impl ManagedConnection {
+    pub(crate) async fn new(client: RedisClient, config: ReconnectConfig) -> Result<Self> {
+        let connection = client
+            .get_connection()
+            .await
+            .map_err(|e| eyre!("redis connect failed: {e}"))?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                client,
+                config,
+                connection: RwLock::new(Some(connection)),
+                reconnecting: AtomicBool::new(false),
+                state: watch::Sender::new(ConnectionState::Connected),
+            }),
+        })
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.shared.state.borrow()
+    }
+
+    /// Receive every change of the connection state.
+    pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.shared.state.subscribe()
+    }
+
+    fn current(&self) -> Result<RedisConnection, RedisError> {
+        self.shared
+            .connection
+            .read()
+            .ok()
+            .and_then(|connection| connection.clone())
+            .ok_or_else(|| {
+                RedisError::from((ErrorKind::IoError, "redis connection lost, reconnecting"))
+            })
+    }
+
+    fn check<T>(&self, result: &Result<T, RedisError>) {
+        if let Err(e) = result {
+            // a master demoted by a sentinel failover answers writes with READONLY
+            if e.is_unrecoverable_error() || e.kind() == ErrorKind::ReadOnly {
+                self.reconnect();
+            }
+        }
+    }
+
+    /// Drop the current connection and reconnect in the background, unless
+    /// already reconnecting.
+    fn reconnect(&self) {
+        if self.shared.reconnecting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Ok(mut connection) = self.shared.connection.write() {
+            *connection = None;
+        }
+        self.shared
+            .state
+            .send_replace(ConnectionState::Reconnecting);
+        warn!("redis connection lost, reconnecting");
+        tokio::spawn(reconnect(Arc::downgrade(&self.shared)));
+    }
+}
+
+async fn reconnect(shared: Weak<Shared>) {
+    let mut attempt = 0;
+    loop {
+        let Some(delay) = shared.upgrade().map(|shared| shared.config.delay(attempt)) else {
+            return;
+        };
+        tokio::time::sleep(delay).await;
+
+        // stop once every handle of the connection is dropped
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        match shared.client.get_connection().await {
+            Ok(new_conn) => {
+                if let Ok(mut connection) = shared.connection.write() {
+                    *connection = Some(new_conn);
+                }
+                shared.reconnecting.store(false, Ordering::Release);
+                shared.state.send_replace(ConnectionState::Connected);
+                metrics::reconnect("redis");
+                info!("redis reconnected after {} attempts", attempt + 1);
+                return;
+            }
+            Err(e) => {
+                warn!("redis reconnect failed: {e}");
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Name `cmd` is recorded under in the operation metrics, e.g. `hgetall`.
+fn operation(cmd: &Cmd) -> String {
+    match cmd.args_iter().next() {
+        Some(Arg::Simple(name)) => String::from_utf8_lossy(name).to_lowercase(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+impl ConnectionLike for ManagedConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let result = metrics::observe("redis", &operation(cmd), async {
+                self.current()?
+                    .req_packed_command(cmd)
+                    .await?
+                    .extract_error()
+            })
+            .await;
+            self.check(&result);
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let result = metrics::observe("redis", "pipeline", async {
+                self.current()?
+                    .req_packed_commands(cmd, offset, count)
+                    .await?
+                    .into_iter()
+                    .map(Value::extract_error)
+                    .collect()
+            })
+            .await;
+            self.check(&result);
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.current()
+            .map(|connection| connection.get_db())
+            .unwrap_or_default()
+    }
+}
+
+/// Dedicated connections for blocking commands like `BLPOP`, which would stall
+/// every other command sharing a multiplexed connection.
+///
+/// Connections are opened on demand, at most `size` at a time.
+pub struct ConnectionPool {
+    client: RedisClient,
+    idle: Mutex<Vec<RedisConnection>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(client: RedisClient, size: usize) -> Self {
+        Self {
+            client,
+            idle: Default::default(),
+            permits: Arc::new(Semaphore::new(size)),
+        }
+    }
+
+    /// Take an idle connection or open a new one, waiting while all `size`
+    /// connections are in use.
+    pub async fn get(self: &Arc<Self>) -> Result<PooledConnection> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| eyre!("redis pool closed: {e}"))?;
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self
+                .client
+                .get_connection()
+                .await
+                .map_err(|e| eyre!("redis connect failed: {e}"))?,
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            pool: self.clone(),
+            broken: false,
+            in_flight: false,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection of a [`ConnectionPool`], returned to the pool when dropped
+/// unless it failed or a command was cancelled before its reply arrived, e.g.
+/// a `BLPOP` under a timeout which would keep blocking the connection.
+pub struct PooledConnection {
+    connection: Option<RedisConnection>,
+    pool: Arc<ConnectionPool>,
+    broken: bool,
+    /// whether a command is waiting for its reply
+    in_flight: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    const fn connection(&mut self) -> &mut RedisConnection {
+        self.connection
+            .as_mut()
+            .expect("connection taken before drop")
+    }
+
+    fn check<T>(&mut self, result: &Result<T, RedisError>) {
+        if let Err(e) = result {
+            self.broken |= e.is_unrecoverable_error();
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if self.broken || self.in_flight {
+            return;
+        }
+        if let (Some(connection), Ok(mut idle)) = (self.connection.take(), self.pool.idle.lock()) {
+            idle.push(connection);
+        }
+    }
+}
+
+impl ConnectionLike for PooledConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            self.in_flight = true;
+            let result = metrics::observe("redis", &operation(cmd), async {
+                self.connection()
+                    .req_packed_command(cmd)
+                    .await?
+                    .extract_error()
+            })
+            .await;
+            self.in_flight = false;
+            self.check(&result);
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            self.in_flight = true;
+            let result = metrics::observe("redis", "pipeline", async {
+                self.connection()
+                    .req_packed_commands(cmd, offset, count)
+                    .await?
+                    .into_iter()
+                    .map(Value::extract_error)
+                    .collect()
+            })
+            .await;
+            self.in_flight = false;
+            self.check(&result);
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.connection
+            .as_ref()
+            .map(|connection| connection.get_db())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::AsyncCommands;
+
+    use super::*;
+    use crate::redis::fake::FakeRedis;
+
+    #[tokio::test]
+    async fn cancelled_command_discards_connection() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let mut conn = redis.blocking_conn().await.unwrap();
+        let blpop = conn.blpop::<_, Option<(String, String)>>("queue", 0.0);
+        assert!(tokio::time::timeout(Duration::from_millis(100), blpop)
+            .await
+            .is_err());
+        drop(conn);
+        assert!(redis.pool.idle.lock().unwrap().is_empty());
+
+        // a connection still blocked in BLPOP would not answer
+        let mut conn = redis.blocking_conn().await.unwrap();
+        let value = tokio::time::timeout(
+            Duration::from_secs(5),
+            conn.rpush::<_, _, u64>("queue", "a"),
+        )
+        .await
+        .expect("fresh connection")
+        .unwrap();
+        assert_eq!(value, 1);
+        drop(conn);
+        assert_eq!(redis.pool.idle.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn commands_are_recorded() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let (rpush, rpush_failed) = metrics::operations("redis", "rpush");
+        let (pipeline, _) = metrics::operations("redis", "pipeline");
+
+        let mut conn = redis.conn();
+        conn.set::<_, _, ()>("recorded", "value").await.unwrap();
+        // RPUSH to a string fails with WRONGTYPE
+        assert!(conn.rpush::<_, _, u64>("recorded", "a").await.is_err());
+        let _: (String, String) = redis::pipe()
+            .cmd("PING")
+            .cmd("PING")
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        let mut pooled = redis.blocking_conn().await.unwrap();
+        assert!(pooled.rpush::<_, _, u64>("recorded", "b").await.is_err());
+
+        let (rpush_after, rpush_failed_after) = metrics::operations("redis", "rpush");
+        assert!(rpush_after >= rpush + 2);
+        assert!(rpush_failed_after >= rpush_failed + 2);
+        assert!(metrics::operations("redis", "pipeline").0 > pipeline);
+    }
+}
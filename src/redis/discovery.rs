@@ -0,0 +1,234 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use redis::{AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::warn;
+
+use super::Redis;
+use crate::{
+    codec::{Codec, Json},
+    metrics,
+    service_discovery::{instances_key, ServiceDiscovery, ServiceInstance},
+};
+
+/// How often [`ServiceDiscovery::watch`] reads the instances again.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reads the clock of the master of `KEYS[1]`, a script so it is routed by the
+/// key in a cluster.
+const TIME_SCRIPT: &str = "return redis.call('TIME')";
+
+/// Removes the expired instances.
+const PRUNE_SCRIPT: &str = r"
+local now = tonumber(redis.call('TIME')[1])
+local entries = redis.call('HGETALL', KEYS[1])
+local pruned = 0
+for i = 1, #entries, 2 do
+    local ok, registration = pcall(cjson.decode, entries[i + 1])
+    if ok and type(registration) == 'table' and type(registration.expires_at) == 'number'
+        and registration.expires_at <= now then
+        pruned = pruned + redis.call('HDEL', KEYS[1], entries[i])
+    end
+end
+return pruned
+";
+
+/// Hash fields cannot expire, so every record carries its own expiry.
+#[derive(Serialize, Deserialize)]
+struct Registration {
+    #[serde(flatten)]
+    instance: ServiceInstance,
+    /// unix timestamp in seconds by the clock of the master
+    expires_at: u64,
+}
+
+impl Redis {
+    /// Unix timestamp in seconds by the clock of the master of `key`, expiries
+    /// are written and compared by it so clock skew between the instances
+    /// does not matter.
+    async fn now(&self, key: &str) -> Result<u64> {
+        let (seconds, _micros): (u64, u64) = metrics::observe(
+            "redis",
+            "time",
+            Script::new(TIME_SCRIPT)
+                .key(key)
+                .invoke_async(&mut self.conn()),
+        )
+        .await
+        .map_err(|e| eyre!("redis time failed: {e}"))?;
+        Ok(seconds)
+    }
+
+    /// Record `instance` for [`ServiceDiscovery`] until `ttl` seconds from now.
+    ///
+    /// The hash expires with the last instance registered, expired instances
+    /// are removed from it on the way.
+    pub(super) async fn register_instance(
+        &self,
+        service_name: &str,
+        instance: ServiceInstance,
+        ttl: u64,
+    ) -> Result<()> {
+        let key = instances_key(service_name);
+        let registration = Registration {
+            expires_at: self.now(&key).await? + ttl,
+            instance,
+        };
+        let mut conn = self.conn();
+        conn.hset::<_, _, _, ()>(
+            &key,
+            &registration.instance.id,
+            Json::encode(&registration)?,
+        )
+        .await
+        .map_err(|e| eyre!("redis hset failed: {e}"))?;
+        let remaining: i64 = conn
+            .ttl(&key)
+            .await
+            .map_err(|e| eyre!("redis ttl failed: {e}"))?;
+        if remaining < ttl as i64 {
+            conn.expire::<_, ()>(&key, ttl as i64)
+                .await
+                .map_err(|e| eyre!("redis expire failed: {e}"))?;
+        }
+        let pruned: Result<u64, _> = metrics::observe(
+            "redis",
+            "prune",
+            Script::new(PRUNE_SCRIPT).key(&key).invoke_async(&mut conn),
+        )
+        .await;
+        if let Err(e) = pruned {
+            warn!("redis prune service instances `{key}` failed: {e}");
+        }
+        Ok(())
+    }
+
+    pub(super) async fn deregister_instance(
+        &self,
+        service_name: &str,
+        instance_id: &str,
+    ) -> Result<()> {
+        self.conn()
+            .hdel::<_, _, ()>(instances_key(service_name), instance_id)
+            .await
+            .map_err(|e| eyre!("redis hdel failed: {e}"))
+    }
+}
+
+impl ServiceDiscovery for Redis {
+    /// Expired instances are skipped, they are removed when an instance of
+    /// the service registers.
+    async fn resolve(&self, service_name: &str) -> Result<Vec<ServiceInstance>> {
+        let key = instances_key(service_name);
+        let entries: Vec<(String, Vec<u8>)> = self
+            .read_conn()
+            .hgetall(&key)
+            .await
+            .map_err(|e| eyre!("redis hgetall failed: {e}"))?;
+        let now = self.now(&key).await?;
+        let mut instances = Vec::with_capacity(entries.len());
+        for (id, value) in entries {
+            match Json::decode::<Registration>(&value) {
+                Ok(registration) if registration.expires_at > now => {
+                    instances.push(registration.instance)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("skip service instance `{key}` `{id}`: {e}"),
+            }
+        }
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(instances)
+    }
+
+    /// Instances are read again every 5 seconds.
+    async fn watch(&self, service_name: &str) -> Result<watch::Receiver<Vec<ServiceInstance>>> {
+        let (instances_tx, instances_rx) = watch::channel(self.resolve(service_name).await?);
+        let redis = self.clone();
+        let service_name = service_name.to_owned();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = instances_tx.closed() => return,
+                    _ = interval.tick() => {}
+                }
+                match redis.resolve(&service_name).await {
+                    Ok(instances) => {
+                        instances_tx.send_if_modified(|current| {
+                            if *current == instances {
+                                return false;
+                            }
+                            *current = instances;
+                            true
+                        });
+                    }
+                    Err(e) => warn!("{e}"),
+                }
+            }
+        });
+        Ok(instances_rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::fake::FakeRedis;
+
+    fn instance(id: &str) -> ServiceInstance {
+        ServiceInstance {
+            id: id.to_owned(),
+            url: format!("http://{id}:8080"),
+            tags: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn expiry_follows_the_server_clock() {
+        let (fake, redis) = FakeRedis::redis().await;
+        // far behind the local clock, which would see every instance expired
+        fake.set_clock_offset(-3600);
+        redis
+            .register_instance("service", instance("a"), 10)
+            .await
+            .unwrap();
+        assert_eq!(redis.resolve("service").await.unwrap(), [instance("a")]);
+        // far ahead, which would see every instance alive
+        fake.set_clock_offset(3600);
+        assert!(redis.resolve("service").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn register_prunes_expired_instances() {
+        let (fake, redis) = FakeRedis::redis().await;
+        redis
+            .register_instance("service", instance("a"), 10)
+            .await
+            .unwrap();
+        fake.set_clock_offset(60);
+        redis
+            .register_instance("service", instance("b"), 10)
+            .await
+            .unwrap();
+        let ids: Vec<String> = redis.conn().hkeys(instances_key("service")).await.unwrap();
+        assert_eq!(ids, ["b"]);
+        assert_eq!(redis.resolve("service").await.unwrap(), [instance("b")]);
+    }
+}
@@ -0,0 +1,573 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-process stand-in for a Redis server in tests, speaks RESP2 and knows
+//! the commands used by this crate, scripts run in an embedded Lua.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use mlua::{Lua, Value as LuaValue, Variadic};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+};
+
+use super::{Redis, RedisConfig, RedisMode};
+
+enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+}
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<Vec<u8>, Entry>,
+    scripts: HashMap<String, Vec<u8>>,
+    /// seconds added to the local clock for `TIME`
+    clock_offset: i64,
+}
+
+impl Store {
+    fn entry(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        if self
+            .entries
+            .get(key)
+            .and_then(|entry| entry.expires)
+            .is_some_and(|expires| expires <= Instant::now())
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.entry(key) {
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8], ttl: Option<Duration>) {
+        self.entries.insert(
+            key.to_vec(),
+            Entry {
+                value: Value::String(value.to_vec()),
+                expires: ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+    }
+
+    fn incr(&mut self, key: &[u8]) -> i64 {
+        let value = self
+            .get(key)
+            .and_then(|value| String::from_utf8(value).ok()?.parse::<i64>().ok())
+            .unwrap_or_default()
+            + 1;
+        self.set(key, value.to_string().as_bytes(), None);
+        value
+    }
+
+    fn hash(&mut self, key: &[u8]) -> Option<&mut BTreeMap<Vec<u8>, Vec<u8>>> {
+        match self.entry(key) {
+            Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn pexpire(&mut self, key: &[u8], millis: u64) -> bool {
+        match self.entry(key) {
+            Some(entry) => {
+                entry.expires = Some(Instant::now() + Duration::from_millis(millis));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lpop(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.entry(key) {
+            Some(Entry {
+                value: Value::List(list),
+                ..
+            }) => list.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Run a command other than the blocking ones, also for `redis.call`.
+    fn command(&mut self, command: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+        let args = &command[1..];
+        match name.as_str() {
+            "PING" => Reply::Bulk(b"PONG".to_vec()),
+            "GET" => self.get(&args[0]).map_or(Reply::Nil, Reply::Bulk),
+            "SET" => {
+                let options = args[2..]
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg).to_uppercase())
+                    .collect::<Vec<_>>();
+                if options.iter().any(|option| option == "NX") && self.get(&args[0]).is_some() {
+                    return Reply::Nil;
+                }
+                let ttl = match options.iter().position(|option| option == "PX") {
+                    Some(i) => match parse::<u64>(&args[i + 3]) {
+                        0 => return Reply::error("ERR invalid expire time in 'set' command"),
+                        millis => Some(Duration::from_millis(millis)),
+                    },
+                    None => None,
+                };
+                self.set(&args[0], &args[1], ttl);
+                Reply::Ok
+            }
+            "SETEX" => {
+                self.set(
+                    &args[0],
+                    &args[2],
+                    Some(Duration::from_secs(parse(&args[1]))),
+                );
+                Reply::Ok
+            }
+            "DEL" => Reply::Integer(
+                args.iter()
+                    .filter(|key| self.entries.remove(*key).is_some())
+                    .count() as i64,
+            ),
+            "INCR" => Reply::Integer(self.incr(&args[0])),
+            "PEXPIRE" => Reply::Integer(self.pexpire(&args[0], parse(&args[1])) as i64),
+            "EXPIRE" => {
+                Reply::Integer(self.pexpire(&args[0], parse::<u64>(&args[1]) * 1000) as i64)
+            }
+            "TTL" => match self.entry(&args[0]) {
+                Some(Entry {
+                    expires: Some(expires),
+                    ..
+                }) => Reply::Integer(
+                    expires
+                        .saturating_duration_since(Instant::now())
+                        .as_secs_f64()
+                        .round() as i64,
+                ),
+                Some(_) => Reply::Integer(-1),
+                None => Reply::Integer(-2),
+            },
+            "TIME" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let seconds = now.as_secs() as i64 + self.clock_offset;
+                Reply::Array(vec![
+                    Reply::Bulk(seconds.to_string().into_bytes()),
+                    Reply::Bulk(now.subsec_micros().to_string().into_bytes()),
+                ])
+            }
+            "HSET" => {
+                if self.hash(&args[0]).is_none() {
+                    self.entries.insert(
+                        args[0].clone(),
+                        Entry {
+                            value: Value::Hash(BTreeMap::new()),
+                            expires: None,
+                        },
+                    );
+                }
+                let Some(hash) = self.hash(&args[0]) else {
+                    return Reply::error("WRONGTYPE");
+                };
+                let added = args[1..]
+                    .chunks(2)
+                    .filter(|field| hash.insert(field[0].clone(), field[1].clone()).is_none())
+                    .count();
+                Reply::Integer(added as i64)
+            }
+            "HGETALL" => Reply::Array(
+                self.hash(&args[0])
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|(field, value)| {
+                        [Reply::Bulk(field.clone()), Reply::Bulk(value.clone())]
+                    })
+                    .collect(),
+            ),
+            "HKEYS" => Reply::Array(
+                self.hash(&args[0])
+                    .into_iter()
+                    .flatten()
+                    .map(|(field, _)| Reply::Bulk(field.clone()))
+                    .collect(),
+            ),
+            "HDEL" => {
+                let Some(hash) = self.hash(&args[0]) else {
+                    return Reply::Integer(0);
+                };
+                let deleted = args[1..]
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count();
+                Reply::Integer(deleted as i64)
+            }
+            "RPUSH" => {
+                let entry = self.entries.entry(args[0].clone()).or_insert(Entry {
+                    value: Value::List(VecDeque::new()),
+                    expires: None,
+                });
+                let Value::List(list) = &mut entry.value else {
+                    return Reply::error("WRONGTYPE");
+                };
+                list.extend(args[1..].iter().cloned());
+                Reply::Integer(list.len() as i64)
+            }
+            "SCRIPT" => {
+                let sha = sha1_smol::Sha1::from(&args[1]).digest().to_string();
+                self.scripts.insert(sha.clone(), args[1].clone());
+                Reply::Bulk(sha.into_bytes())
+            }
+            "EVALSHA" | "EVAL" => {
+                let script = if name == "EVAL" {
+                    args[0].clone()
+                } else {
+                    match self.scripts.get(&*String::from_utf8_lossy(&args[0])) {
+                        Some(script) => script.clone(),
+                        None => return Reply::error("NOSCRIPT No matching script"),
+                    }
+                };
+                let keys = parse::<usize>(&args[1]);
+                self.eval(&script, &args[2..2 + keys], &args[2 + keys..])
+                    .unwrap_or_else(|e| Reply::Error(format!("ERR {e}")))
+            }
+            // CLIENT SETINFO, SELECT and the like
+            _ => Reply::Ok,
+        }
+    }
+
+    /// Run a script in Lua 5.1 like Redis does, with `redis.call` served by
+    /// this store and replies converted by the rules of the Redis Lua API.
+    fn eval(&mut self, script: &[u8], keys: &[Vec<u8>], args: &[Vec<u8>]) -> mlua::Result<Reply> {
+        let lua = Lua::new();
+        let strings = |values: &[Vec<u8>]| {
+            lua.create_sequence_from(
+                values
+                    .iter()
+                    .map(|value| lua.create_string(value))
+                    .collect::<mlua::Result<Vec<_>>>()?,
+            )
+        };
+        lua.globals().set("KEYS", strings(keys)?)?;
+        lua.globals().set("ARGV", strings(args)?)?;
+        lua.scope(|scope| {
+            let call = scope.create_function_mut(|lua, args: Variadic<LuaValue>| {
+                let command = args
+                    .iter()
+                    .map(|arg| match arg {
+                        LuaValue::String(arg) => Ok(arg.as_bytes().to_vec()),
+                        LuaValue::Integer(arg) => Ok(arg.to_string().into_bytes()),
+                        LuaValue::Number(arg) => Ok(arg.to_string().into_bytes()),
+                        _ => Err(mlua::Error::runtime(
+                            "Lua redis lib command arguments must be strings or integers",
+                        )),
+                    })
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                match self.command(&command) {
+                    Reply::Error(e) => Err(mlua::Error::runtime(e)),
+                    reply => reply.into_lua(lua),
+                }
+            })?;
+            let redis = lua.create_table()?;
+            redis.set("call", call)?;
+            lua.globals().set("redis", redis)?;
+            let cjson = lua.create_table()?;
+            cjson.set(
+                "decode",
+                lua.create_function(|lua, json: mlua::String| {
+                    let json = serde_json::from_slice(json.as_bytes())
+                        .map_err(|e| mlua::Error::runtime(e.to_string()))?;
+                    json_into_lua(lua, json)
+                })?,
+            )?;
+            lua.globals().set("cjson", cjson)?;
+            Reply::from_lua(lua.load(script).eval()?)
+        })
+    }
+}
+
+enum Reply {
+    Ok,
+    Nil,
+    NilArray,
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    Status(String),
+    Error(String),
+}
+
+impl Reply {
+    fn error(message: &str) -> Self {
+        Self::Error(message.to_owned())
+    }
+
+    fn encode(self) -> Vec<u8> {
+        match self {
+            Self::Ok => b"+OK\r\n".to_vec(),
+            Self::Nil => b"$-1\r\n".to_vec(),
+            Self::NilArray => b"*-1\r\n".to_vec(),
+            Self::Integer(value) => format!(":{value}\r\n").into_bytes(),
+            Self::Bulk(value) => {
+                [format!("${}\r\n", value.len()).as_bytes(), &value, b"\r\n"].concat()
+            }
+            Self::Array(values) => {
+                let mut encoded = format!("*{}\r\n", values.len()).into_bytes();
+                for value in values {
+                    encoded.extend(value.encode());
+                }
+                encoded
+            }
+            Self::Status(status) => format!("+{status}\r\n").into_bytes(),
+            Self::Error(message) => format!("-{message}\r\n").into_bytes(),
+        }
+    }
+
+    /// Redis reply to Lua: nil is false and a status is an `ok` table.
+    fn into_lua(self, lua: &Lua) -> mlua::Result<LuaValue<'_>> {
+        let status = |status: &str| {
+            let table = lua.create_table()?;
+            table.set("ok", status)?;
+            Ok(LuaValue::Table(table))
+        };
+        match self {
+            Self::Ok => status("OK"),
+            Self::Status(value) => status(&value),
+            Self::Nil | Self::NilArray => Ok(LuaValue::Boolean(false)),
+            Self::Integer(value) => Ok(LuaValue::Integer(value)),
+            Self::Bulk(value) => lua.create_string(value).map(LuaValue::String),
+            Self::Array(values) => lua
+                .create_sequence_from(
+                    values
+                        .into_iter()
+                        .map(|value| value.into_lua(lua))
+                        .collect::<mlua::Result<Vec<_>>>()?,
+                )
+                .map(LuaValue::Table),
+            Self::Error(message) => {
+                let table = lua.create_table()?;
+                table.set("err", message)?;
+                Ok(LuaValue::Table(table))
+            }
+        }
+    }
+
+    /// Lua to Redis reply: false is nil, true is 1 and numbers are truncated.
+    fn from_lua(value: LuaValue) -> mlua::Result<Self> {
+        Ok(match value {
+            LuaValue::Nil | LuaValue::Boolean(false) => Self::Nil,
+            LuaValue::Boolean(true) => Self::Integer(1),
+            LuaValue::Integer(value) => Self::Integer(value),
+            LuaValue::Number(value) => Self::Integer(value as i64),
+            LuaValue::String(value) => Self::Bulk(value.as_bytes().to_vec()),
+            LuaValue::Table(table) => {
+                if let Some(message) = table.raw_get::<_, Option<String>>("err")? {
+                    Self::Error(message)
+                } else if let Some(status) = table.raw_get::<_, Option<String>>("ok")? {
+                    Self::Status(status)
+                } else {
+                    Self::Array(
+                        table
+                            .sequence_values()
+                            .map(|value| Self::from_lua(value?))
+                            .collect::<mlua::Result<_>>()?,
+                    )
+                }
+            }
+            _ => Self::Nil,
+        })
+    }
+}
+
+/// `cjson.decode` of the Redis Lua API, null is nil.
+fn json_into_lua(lua: &Lua, json: serde_json::Value) -> mlua::Result<LuaValue<'_>> {
+    Ok(match json {
+        serde_json::Value::Null => LuaValue::Nil,
+        serde_json::Value::Bool(value) => LuaValue::Boolean(value),
+        serde_json::Value::Number(value) => LuaValue::Number(value.as_f64().unwrap_or_default()),
+        serde_json::Value::String(value) => LuaValue::String(lua.create_string(value)?),
+        serde_json::Value::Array(values) => LuaValue::Table(
+            lua.create_sequence_from(
+                values
+                    .into_iter()
+                    .map(|value| json_into_lua(lua, value))
+                    .collect::<mlua::Result<Vec<_>>>()?,
+            )?,
+        ),
+        serde_json::Value::Object(fields) => {
+            let table = lua.create_table()?;
+            for (field, value) in fields {
+                table.set(field, json_into_lua(lua, value)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> T {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or_else(|| panic!("invalid argument {arg:?}"))
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct FakeRedis {
+    store: Arc<Mutex<Store>>,
+    /// woken when a list is pushed to
+    pushed: Arc<Notify>,
+}
+
+impl FakeRedis {
+    /// Listen on a free local port.
+    pub(crate) async fn start() -> (Self, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let fake = Self::default();
+        let server = fake.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+        (fake, url)
+    }
+
+    /// A [`Redis`] connected to a new fake server.
+    pub(crate) async fn redis() -> (Self, Redis) {
+        let (fake, url) = Self::start().await;
+        let config = RedisConfig {
+            mode: RedisMode::Standalone,
+            endpoints: vec![url],
+            ..Default::default()
+        };
+        (fake, Redis::new(&config).await.unwrap())
+    }
+
+    pub(crate) fn exists(&self, key: &str) -> bool {
+        self.store.lock().unwrap().entry(key.as_bytes()).is_some()
+    }
+
+    pub(crate) fn keys(&self, prefix: &str) -> Vec<String> {
+        let mut store = self.store.lock().unwrap();
+        let keys = store
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(prefix.as_bytes()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut keys = keys
+            .into_iter()
+            .filter(|key| store.entry(key).is_some())
+            .map(|key| String::from_utf8_lossy(&key).into_owned())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    /// Move the clock of the server, expiries of keys are not affected.
+    pub(crate) fn set_clock_offset(&self, seconds: i64) {
+        self.store.lock().unwrap().clock_offset = seconds;
+    }
+
+    pub(crate) fn delete(&self, key: &str) {
+        self.store.lock().unwrap().entries.remove(key.as_bytes());
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        while let Some(command) = read_command(&mut reader).await {
+            let reply = self.execute(command).await;
+            if writer.write_all(&reply.encode()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn execute(&self, command: Vec<Vec<u8>>) -> Reply {
+        if command[0].eq_ignore_ascii_case(b"BLPOP") {
+            return self.blpop(&command[1..]).await;
+        }
+        let reply = self.store.lock().unwrap().command(&command);
+        // a push, also one from a script, may unblock a BLPOP
+        self.pushed.notify_waiters();
+        reply
+    }
+
+    async fn blpop(&self, args: &[Vec<u8>]) -> Reply {
+        let (keys, timeout) = args.split_at(args.len() - 1);
+        let timeout = parse::<f64>(&timeout[0]);
+        let deadline = (timeout > 0.0).then(|| Instant::now() + Duration::from_secs_f64(timeout));
+        loop {
+            let pushed = self.pushed.notified();
+            {
+                let mut store = self.store.lock().unwrap();
+                for key in keys {
+                    if let Some(value) = store.lpop(key) {
+                        return Reply::Array(vec![Reply::Bulk(key.clone()), Reply::Bulk(value)]);
+                    }
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline.into(), pushed)
+                        .await
+                        .is_err()
+                    {
+                        return Reply::NilArray;
+                    }
+                }
+                None => pushed.await,
+            }
+        }
+    }
+}
+
+async fn read_command(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let count = line.trim_end().strip_prefix('*')?.parse::<usize>().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len = line.trim_end().strip_prefix('$')?.parse::<usize>().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        command.push(arg);
+    }
+    Some(command)
+}
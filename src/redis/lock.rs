@@ -0,0 +1,394 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use redis::Script;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{debug, warn};
+
+use super::{ManagedConnection, Redis};
+use crate::metrics;
+
+/// Sets the owner token and hands out the next fencing token, atomically so a
+/// token is only consumed by an acquisition.
+const ACQUIRE_SCRIPT: &str = r"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return redis.call('INCR', KEYS[2])
+end
+return false
+";
+
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+const RENEW_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// Mutual exclusion across processes sharing a Redis deployment.
+///
+/// A lock held by a crashed process expires after `ttl`, while a live holder
+/// renews it every `ttl / 3`. Each acquisition gets a fencing token larger than
+/// all earlier ones, pass it to the protected resource so it can reject writes
+/// of a holder which lost the lock without noticing, e.g. during a long pause.
+#[derive(Clone)]
+pub struct RedisLock {
+    connection: ManagedConnection,
+    key: String,
+    fencing_key: String,
+    ttl: Duration,
+    acquire: Script,
+    release: Script,
+    renew: Script,
+}
+
+impl RedisLock {
+    /// `ttl` is sent in milliseconds and must be at least one.
+    pub fn new(redis: &Redis, name: &str, ttl: Duration) -> Result<Self> {
+        if ttl < Duration::from_millis(1) {
+            return Err(eyre!("redis lock `{name}` ttl {ttl:?} is below 1ms"));
+        }
+        // the hash tag keeps both keys in one cluster slot for the scripts
+        let key = format!("lock:{{{name}}}");
+        Ok(Self {
+            connection: redis.conn(),
+            fencing_key: format!("{key}:fencing"),
+            key,
+            ttl,
+            acquire: Script::new(ACQUIRE_SCRIPT),
+            release: Script::new(RELEASE_SCRIPT),
+            renew: Script::new(RENEW_SCRIPT),
+        })
+    }
+
+    /// Acquire the lock if it is free.
+    pub async fn try_acquire(&self) -> Result<Option<RedisLockGuard>> {
+        let owner = owner_token();
+        let fencing_token: Option<u64> = metrics::observe(
+            "redis",
+            "lock_acquire",
+            self.acquire
+                .key(&self.key)
+                .key(&self.fencing_key)
+                .arg(&owner)
+                .arg(self.ttl.as_millis() as u64)
+                .invoke_async(&mut self.connection.clone()),
+        )
+        .await
+        .map_err(|e| eyre!("redis lock `{}` acquire failed: {e}", self.key))?;
+        Ok(fencing_token.map(|fencing_token| {
+            debug!("redis lock `{}` acquired: {fencing_token}", self.key);
+            RedisLockGuard::new(self.clone(), owner, fencing_token)
+        }))
+    }
+
+    /// Acquire the lock, retrying while it is held elsewhere for at most `timeout`.
+    pub async fn acquire(&self, timeout: Duration) -> Result<RedisLockGuard> {
+        let deadline = Instant::now() + timeout;
+        let retry_interval =
+            (self.ttl / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        loop {
+            if let Some(guard) = self.try_acquire().await? {
+                return Ok(guard);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(eyre!(
+                    "redis lock `{}` acquire timed out after {timeout:?}",
+                    self.key
+                ));
+            }
+            tokio::time::sleep(retry_interval.min(deadline - now)).await;
+        }
+    }
+
+    async fn release_owned(&self, owner: &str) -> Result<bool> {
+        let released: u64 = metrics::observe(
+            "redis",
+            "lock_release",
+            self.release
+                .key(&self.key)
+                .arg(owner)
+                .invoke_async(&mut self.connection.clone()),
+        )
+        .await
+        .map_err(|e| eyre!("redis lock `{}` release failed: {e}", self.key))?;
+        Ok(released == 1)
+    }
+
+    async fn renew_owned(&self, owner: &str) -> Result<bool> {
+        let renewed: u64 = self
+            .renew
+            .key(&self.key)
+            .arg(owner)
+            .arg(self.ttl.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|e| eyre!("redis lock `{}` renew failed: {e}", self.key))?;
+        Ok(renewed == 1)
+    }
+}
+
+/// Unique per acquisition, only the holder of the token may renew or release.
+fn owner_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let random = RandomState::new().build_hasher().finish();
+    format!("{}-{nanos:x}-{random:016x}", std::process::id())
+}
+
+/// A held [`RedisLock`], renewed in the background and released when dropped.
+///
+/// Dropping releases the lock in a spawned task, call [`Self::release`] to
+/// wait for it.
+pub struct RedisLockGuard {
+    lock: RedisLock,
+    owner: String,
+    fencing_token: u64,
+    lost: watch::Receiver<bool>,
+    renewal: JoinHandle<()>,
+    released: bool,
+}
+
+impl RedisLockGuard {
+    fn new(lock: RedisLock, owner: String, fencing_token: u64) -> Self {
+        let (lost_tx, lost) = watch::channel(false);
+        let renewal = tokio::spawn(renew(lock.clone(), owner.clone(), lost_tx));
+        Self {
+            lock,
+            owner,
+            fencing_token,
+            lost,
+            renewal,
+            released: false,
+        }
+    }
+
+    pub const fn fencing_token(&self) -> u64 {
+        self.fencing_token
+    }
+
+    /// Whether the lock expired or was taken over since it was acquired.
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Wait until the lock is lost, e.g. to cancel the work it protects.
+    pub async fn lost(&self) {
+        let _ = self.lost.clone().wait_for(|lost| *lost).await;
+    }
+
+    /// Stop renewing and release the lock, returns false if it was already lost.
+    pub async fn release(mut self) -> Result<bool> {
+        self.renewal.abort();
+        self.released = true;
+        self.lock.release_owned(&self.owner).await
+    }
+}
+
+impl Drop for RedisLockGuard {
+    fn drop(&mut self) {
+        self.renewal.abort();
+        if self.released {
+            return;
+        }
+        let lock = self.lock.clone();
+        let owner = std::mem::take(&mut self.owner);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = lock.release_owned(&owner).await {
+                        warn!("{e}");
+                    }
+                });
+            }
+            // the lock expires after its ttl
+            Err(_) => warn!("redis lock `{}` dropped outside a runtime", lock.key),
+        }
+    }
+}
+
+async fn renew(lock: RedisLock, owner: String, lost: watch::Sender<bool>) {
+    let interval = lock.ttl / 3;
+    let mut expires = Instant::now() + lock.ttl;
+    loop {
+        tokio::time::sleep(interval).await;
+        let renewed_at = Instant::now();
+        match lock.renew_owned(&owner).await {
+            Ok(true) => expires = renewed_at + lock.ttl,
+            Ok(false) => {
+                warn!("redis lock `{}` lost", lock.key);
+                lost.send_replace(true);
+                return;
+            }
+            // keep trying while the lock may still be held
+            Err(e) if Instant::now() < expires => warn!("{e}"),
+            Err(e) => {
+                warn!("redis lock `{}` lost: {e}", lock.key);
+                lost.send_replace(true);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::fake::FakeRedis;
+
+    const TTL: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn acquire_and_release() {
+        let (fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "acquire", TTL).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        assert!(fake.exists("lock:{acquire}"));
+        assert!(!guard.is_lost());
+        assert!(guard.release().await.unwrap());
+        assert!(!fake.exists("lock:{acquire}"));
+    }
+
+    #[tokio::test]
+    async fn contention() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "contention", TTL).unwrap();
+        let other = RedisLock::new(&redis, "contention", TTL).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        assert!(other.try_acquire().await.unwrap().is_none());
+        guard.release().await.unwrap();
+        assert!(other.try_acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn acquire_times_out() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "timeout", TTL).unwrap();
+        let _guard = lock.try_acquire().await.unwrap().unwrap();
+        let started = Instant::now();
+        let e = lock
+            .acquire(Duration::from_millis(100))
+            .await
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("timed out"), "{e}");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_release() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "wait", TTL).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            guard.release().await.unwrap();
+        });
+        lock.acquire(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fencing_tokens_increase() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "fencing", TTL).unwrap();
+        let mut last = 0;
+        for _ in 0..3 {
+            let guard = lock.try_acquire().await.unwrap().unwrap();
+            assert!(guard.fencing_token() > last);
+            last = guard.fencing_token();
+            guard.release().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn release_by_other_owner_is_rejected() {
+        let (fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "owner", TTL).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        assert!(!lock.release_owned(&owner_token()).await.unwrap());
+        assert!(!lock.renew_owned(&owner_token()).await.unwrap());
+        assert!(fake.exists("lock:{owner}"));
+        assert!(guard.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn renewal_outlives_ttl() {
+        let (fake, redis) = FakeRedis::redis().await;
+        let ttl = Duration::from_millis(300);
+        let lock = RedisLock::new(&redis, "renew", ttl).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        tokio::time::sleep(ttl * 4).await;
+        assert!(!guard.is_lost());
+        assert!(fake.exists("lock:{renew}"));
+        assert!(lock.try_acquire().await.unwrap().is_none());
+        assert!(guard.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sub_millisecond_ttl_is_rejected() {
+        let (_fake, redis) = FakeRedis::redis().await;
+        for ttl in [Duration::ZERO, Duration::from_micros(999)] {
+            let e = RedisLock::new(&redis, "ttl", ttl).err().unwrap();
+            assert!(e.to_string().contains("below 1ms"), "{e}");
+        }
+        assert!(RedisLock::new(&redis, "ttl", Duration::from_millis(1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_lock_is_lost() {
+        let (fake, redis) = FakeRedis::redis().await;
+        let ttl = Duration::from_millis(300);
+        let lock = RedisLock::new(&redis, "expire", ttl).unwrap();
+        let guard = lock.try_acquire().await.unwrap().unwrap();
+        // expire the key between renewals as a long pause of the holder would
+        fake.delete("lock:{expire}");
+        let other = lock.try_acquire().await.unwrap().unwrap();
+        assert!(other.fencing_token() > guard.fencing_token());
+        tokio::time::timeout(ttl * 2, guard.lost()).await.unwrap();
+        assert!(guard.is_lost());
+        assert!(!guard.release().await.unwrap());
+        assert!(fake.exists("lock:{expire}"));
+        assert!(other.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn dropped_guard_releases() {
+        let (fake, redis) = FakeRedis::redis().await;
+        let lock = RedisLock::new(&redis, "drop", TTL).unwrap();
+        drop(lock.try_acquire().await.unwrap().unwrap());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while fake.exists("lock:{drop}") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
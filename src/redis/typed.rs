@@ -0,0 +1,144 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use color_eyre::{eyre::eyre, Result};
+use redis::{AsyncCommands, ToRedisArgs};
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "bincode")]
+use crate::codec::Bincode;
+#[cfg(feature = "msgpack")]
+use crate::codec::MsgPack;
+use crate::{
+    codec::{Codec, Json},
+    repository::RepositoryStore,
+};
+
+use super::Redis;
+
+impl Redis {
+    /// Read `key` decoded with `C`, `None` if it does not exist.
+    pub async fn get_as<C: Codec, T: DeserializeOwned>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+    ) -> Result<Option<T>> {
+        let bytes: Option<Vec<u8>> = self
+            .conn()
+            .get(key)
+            .await
+            .map_err(|e| eyre!("redis get failed: {e}"))?;
+        bytes.map(|bytes| C::decode(&bytes)).transpose()
+    }
+
+    /// Write `value` encoded with `C`, expiring after `ttl` seconds unless it is 0.
+    pub async fn put_as<C: Codec, T: Serialize>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+        value: &T,
+        ttl: u64,
+    ) -> Result<()> {
+        let bytes = C::encode(value)?;
+        let mut conn = self.conn();
+        if ttl == 0 {
+            conn.set(key, bytes).await
+        } else {
+            conn.set_ex(key, bytes, ttl).await
+        }
+        .map_err(|e| eyre!("redis set failed: {e}"))
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+    ) -> Result<Option<T>> {
+        self.get_as::<Json, T>(key).await
+    }
+
+    pub async fn put_json<T: Serialize>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+        value: &T,
+        ttl: u64,
+    ) -> Result<()> {
+        self.put_as::<Json, T>(key, value, ttl).await
+    }
+
+    #[cfg(feature = "bincode")]
+    pub async fn get_bincode<T: DeserializeOwned>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+    ) -> Result<Option<T>> {
+        self.get_as::<Bincode, T>(key).await
+    }
+
+    #[cfg(feature = "bincode")]
+    pub async fn put_bincode<T: Serialize>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+        value: &T,
+        ttl: u64,
+    ) -> Result<()> {
+        self.put_as::<Bincode, T>(key, value, ttl).await
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub async fn get_msgpack<T: DeserializeOwned>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+    ) -> Result<Option<T>> {
+        self.get_as::<MsgPack, T>(key).await
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub async fn put_msgpack<T: Serialize>(
+        &self,
+        key: impl ToRedisArgs + Send + Sync,
+        value: &T,
+        ttl: u64,
+    ) -> Result<()> {
+        self.put_as::<MsgPack, T>(key, value, ttl).await
+    }
+}
+
+impl RepositoryStore for Redis {
+    async fn entry(&self, namespace: &str, id: &str) -> Result<Option<Vec<u8>>> {
+        self.conn()
+            .hget(namespace, id)
+            .await
+            .map_err(|e| eyre!("redis hget failed: {e}"))
+    }
+
+    async fn put_entry(&self, namespace: &str, id: &str, value: Vec<u8>) -> Result<()> {
+        self.conn()
+            .hset(namespace, id, value)
+            .await
+            .map_err(|e| eyre!("redis hset failed: {e}"))
+    }
+
+    async fn delete_entry(&self, namespace: &str, id: &str) -> Result<bool> {
+        let deleted: u64 = self
+            .conn()
+            .hdel(namespace, id)
+            .await
+            .map_err(|e| eyre!("redis hdel failed: {e}"))?;
+        Ok(deleted > 0)
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.conn()
+            .hgetall(namespace)
+            .await
+            .map_err(|e| eyre!("redis hgetall failed: {e}"))
+    }
+}
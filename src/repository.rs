@@ -0,0 +1,112 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, marker::PhantomData};
+
+use color_eyre::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::codec::{Codec, Json};
+
+/// Raw entries grouped by namespace.
+///
+/// Etcd stores an entry under the key `{namespace}/{id}`, Redis as field `id`
+/// of the hash `namespace`.
+pub trait RepositoryStore {
+    fn entry(
+        &self,
+        namespace: &str,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    fn put_entry(
+        &self,
+        namespace: &str,
+        id: &str,
+        value: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns whether the entry existed.
+    fn delete_entry(&self, namespace: &str, id: &str) -> impl Future<Output = Result<bool>> + Send;
+
+    fn entries(
+        &self,
+        namespace: &str,
+    ) -> impl Future<Output = Result<Vec<(String, Vec<u8>)>>> + Send;
+}
+
+/// Values of type `T` under a namespace of an etcd or Redis store, encoded with `C`.
+pub struct Repository<S, T, C = Json> {
+    store: S,
+    namespace: String,
+    _marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl<S: Clone, T, C> Clone for Repository<S, T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            namespace: self.namespace.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S, T, C> Repository<S, T, C>
+where
+    S: RepositoryStore,
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(store: S, namespace: impl Into<String>) -> Self {
+        Self {
+            store,
+            namespace: namespace.into(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<T>> {
+        self.store
+            .entry(&self.namespace, id)
+            .await?
+            .map(|bytes| C::decode(&bytes))
+            .transpose()
+    }
+
+    pub async fn put(&self, id: &str, value: &T) -> Result<()> {
+        self.store
+            .put_entry(&self.namespace, id, C::encode(value)?)
+            .await
+    }
+
+    /// Returns whether the entry existed.
+    pub async fn delete(&self, id: &str) -> Result<bool> {
+        self.store.delete_entry(&self.namespace, id).await
+    }
+
+    /// All entries with their ids.
+    pub async fn list(&self) -> Result<Vec<(String, T)>> {
+        self.store
+            .entries(&self.namespace)
+            .await?
+            .into_iter()
+            .map(|(id, bytes)| Ok((id, C::decode(&bytes)?)))
+            .collect()
+    }
+}
@@ -24,9 +24,9 @@ use color_eyre::{eyre::Error, Result};
 use serde::Serialize;
 use serde_json::json;
 use tokio::{net::TcpListener, signal};
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::error::CALError;
+use crate::{error::CALError, service_register::Registration};
 
 pub use axum;
 pub use axum_extra;
@@ -78,7 +78,187 @@ async fn health() -> impl IntoResponse {
     ok_no_data()
 }
 
+#[cfg(feature = "log")]
+pub use log_level::{log_level_router, LogLevel};
+
+#[cfg(feature = "log")]
+mod log_level {
+    use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+    use serde::{Deserialize, Serialize};
+    use tracing::info;
+
+    use super::{err, ok, RESTfulError};
+    use crate::{error::CALError, log::LogHandle};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct LogLevel {
+        pub filter: String,
+    }
+
+    async fn get_log_level(
+        State(handle): State<LogHandle>,
+    ) -> Result<impl IntoResponse, RESTfulError> {
+        ok(LogLevel {
+            filter: handle.filter()?,
+        })
+    }
+
+    async fn put_log_level(
+        State(handle): State<LogHandle>,
+        Json(level): Json<LogLevel>,
+    ) -> Result<impl IntoResponse, RESTfulError> {
+        if let Err(e) = handle.set_filter(&level.filter) {
+            return err(CALError::BadRequest, &e.to_string());
+        }
+        info!("log filter changed to: {}", level.filter);
+        ok(LogLevel {
+            filter: handle.filter()?,
+        })
+    }
+
+    /// `GET/PUT /log/level` to inspect and change the log filter at runtime,
+    /// merge it into the router passed to `http_serve`.
+    pub fn log_level_router(handle: LogHandle) -> Router {
+        Router::new()
+            .route("/log/level", get(get_log_level).put(put_log_level))
+            .with_state(handle)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::net::SocketAddr;
+
+        use serde_json::Value;
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        use super::*;
+        use crate::log::{subscriber, LogConfig};
+
+        /// Send `method /log/level` with a JSON `body`, returns the status and body.
+        async fn request(addr: SocketAddr, method: &str, body: &str) -> (u16, Value) {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    format!(
+                        "{method} /log/level HTTP/1.1\r\nhost: localhost\r\n\
+                         content-type: application/json\r\ncontent-length: {}\r\n\
+                         connection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let status = response[9..12].parse().unwrap();
+            let (_, body) = response.split_once("\r\n\r\n").unwrap();
+            (status, serde_json::from_str(body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn get_and_put_log_level() {
+            let (_subscriber, handle, _guard) = subscriber("app", &LogConfig::default()).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let router = log_level_router(handle.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+
+            let (status, body) = request(addr, "GET", "").await;
+            assert_eq!(status, 200);
+            assert_eq!(body["data"]["filter"], "info");
+
+            let (status, body) = request(addr, "PUT", r#"{"filter":"debug"}"#).await;
+            assert_eq!(status, 200);
+            assert_eq!(body["data"]["filter"], "debug");
+            assert_eq!(handle.filter().unwrap(), "debug");
+
+            let (status, body) = request(addr, "PUT", r#"{"filter":"debug,="}"#).await;
+            assert_eq!(status, 400);
+            assert!(body["message"]
+                .as_str()
+                .unwrap()
+                .contains("invalid log filter"));
+            assert_eq!(handle.filter().unwrap(), "debug");
+        }
+    }
+}
+
+/// Continue the caller's W3C trace in a span around each request and return
+/// the `traceparent` of that span in the response headers.
+#[cfg(feature = "otel")]
+async fn trace_context(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    use tracing::Instrument;
+
+    let span = tracing::info_span!(
+        "http_request",
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    crate::log::extract_trace_context(&span, request.headers());
+    let mut response = next.run(request).instrument(span.clone()).await;
+    crate::log::inject_trace_context(&span, response.headers_mut());
+    response
+}
+
+/// Serve [`crate::metrics::registry`] in the Prometheus text format.
+#[cfg(feature = "metrics")]
+async fn metrics() -> Result<impl IntoResponse, RESTfulError> {
+    Ok((
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        crate::metrics::gather()?,
+    ))
+}
+
+/// Count requests and observe their latency per route template, so that path
+/// parameters do not turn into separate series.
+#[cfg(feature = "metrics")]
+async fn track_metrics(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let started = std::time::Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let response = next.run(request).await;
+    crate::metrics::observe_http(&method, &route, response.status().as_u16(), started);
+    response
+}
+
 pub async fn http_serve(service_name: &str, port: u16, router: Router) -> Result<()> {
+    serve(service_name, port, router, shutdown_signal()).await
+}
+
+/// Like [`http_serve`], deregisters the service on the shutdown signal before
+/// the pending requests are finished, so no new traffic is routed here.
+pub async fn http_serve_with_registration(
+    service_name: &str,
+    port: u16,
+    router: Router,
+    registration: Registration,
+) -> Result<()> {
+    let shutdown = async {
+        shutdown_signal().await;
+        if let Err(e) = registration.deregister().await {
+            warn!("deregister failed: {e}");
+        }
+    };
+    serve(service_name, port, router, shutdown).await
+}
+
+async fn serve(
+    service_name: &str,
+    port: u16,
+    router: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Result<()> {
     async fn handler_404() -> impl IntoResponse {
         (
             StatusCode::NOT_FOUND,
@@ -90,6 +270,12 @@ pub async fn http_serve(service_name: &str, port: u16, router: Router) -> Result
     }
 
     let router = router.route("/health", get(health)).fallback(handler_404);
+    #[cfg(feature = "metrics")]
+    let router = router
+        .route("/metrics", get(metrics))
+        .layer(axum::middleware::from_fn(track_metrics));
+    #[cfg(feature = "otel")]
+    let router = router.layer(axum::middleware::from_fn(trace_context));
 
     let listener = TcpListener::bind(format!("[::]:{}", port)).await?;
 
@@ -98,7 +284,7 @@ pub async fn http_serve(service_name: &str, port: u16, router: Router) -> Result
         listener.local_addr()?
     );
     axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(shutdown)
         .await?;
 
     Ok(())
@@ -208,3 +394,95 @@ async fn shutdown_signal() {
         _ = terminate => info!("terminate signal received"),
     }
 }
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::{
+        global,
+        trace::{SpanId, TraceId, TracerProvider as _},
+    };
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::log::InMemoryExporter;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[tokio::test]
+    async fn request_continues_trace() {
+        let exported = InMemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exported.clone())
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test"))),
+        );
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve("test", port, Router::new(), async {
+            let _ = shutdown_rx.await;
+        }));
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(
+                format!(
+                    "GET /health HTTP/1.1\r\nhost: localhost\r\n\
+                     traceparent: 00-{TRACE_ID}-{PARENT_ID}-01\r\n\
+                     connection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let spans = exported.spans();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "http_request")
+            .expect("request span exported");
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+
+        let traceparent = response
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("traceparent")
+                    .then(|| value.trim())
+            })
+            .expect("traceparent response header");
+        assert_eq!(
+            traceparent,
+            format!("00-{TRACE_ID}-{}-01", span.span_context.span_id())
+        );
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// An instance registered with [`crate::service_register::ServiceRegister`].
+///
+/// Besides the traefik keys, every instance is recorded under
+/// `services/{service_name}`, etcd stores it at the key
+/// `services/{service_name}/{id}`, Redis as field `id` of that hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceInstance {
+    /// server name under the traefik service
+    pub id: String,
+    pub url: String,
+    /// `tags` of the register config
+    pub tags: Vec<String>,
+}
+
+/// Find the live instances of services, e.g. for client-side load balancing.
+pub trait ServiceDiscovery {
+    /// Instances of `service_name` registered now, sorted by id.
+    fn resolve(
+        &self,
+        service_name: &str,
+    ) -> impl Future<Output = Result<Vec<ServiceInstance>>> + Send;
+
+    /// Instances of `service_name`, updated whenever one registers, changes
+    /// or expires. Watching stops once all receivers are dropped.
+    fn watch(
+        &self,
+        service_name: &str,
+    ) -> impl Future<Output = Result<watch::Receiver<Vec<ServiceInstance>>>> + Send;
+}
+
+pub(crate) fn instances_key(service_name: &str) -> String {
+    format!("services/{service_name}")
+}
@@ -1,3 +1,10 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+};
+
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
@@ -7,6 +14,10 @@ pub struct ServiceRegisterConfig {
     pub url: String,
     pub tags: Vec<String>,
     pub ttl: i64,
+    /// server name of this replica under the traefik service, defaults to the
+    /// host name, e.g. the pod name, so every replica registers its own server,
+    /// or to a random one if there is no host name
+    pub instance_id: Option<String>,
 }
 
 impl Default for ServiceRegisterConfig {
@@ -15,14 +26,76 @@ impl Default for ServiceRegisterConfig {
             tags: Default::default(),
             ttl: 60,
             url: Default::default(),
+            instance_id: Default::default(),
         }
     }
 }
 
+impl ServiceRegisterConfig {
+    /// `instance_id`, otherwise the host name, otherwise `service_name` with a
+    /// random suffix, new for every registration.
+    pub fn instance_id(&self, service_name: &str) -> String {
+        self.instance_id
+            .clone()
+            .or_else(|| {
+                std::env::var("HOSTNAME")
+                    .ok()
+                    .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+                    .map(|hostname| hostname.trim().to_owned())
+                    .filter(|hostname| !hostname.is_empty())
+            })
+            .unwrap_or_else(|| random_instance_id(service_name))
+    }
+}
+
+pub(crate) fn random_instance_id(service_name: &str) -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("{service_name}-{random:016x}")
+}
+
 pub trait ServiceRegister {
     fn keep_service_register(
         &self,
         service_name: &str,
         config: ServiceRegisterConfig,
-    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    ) -> impl Future<Output = Result<Registration>> + Send;
+}
+
+/// Registration kept alive in the background by
+/// [`ServiceRegister::keep_service_register`].
+///
+/// Dropping the handle keeps the registration alive until the process exits,
+/// call [`Self::deregister`] on shutdown so no traffic is routed to this
+/// instance once it is gone.
+pub struct Registration {
+    deregister: Pin<Box<dyn Future<Output = Result<()>> + Send>>,
+}
+
+impl Registration {
+    #[cfg(any(feature = "etcd", feature = "redis"))]
+    pub(crate) fn new(deregister: impl Future<Output = Result<()>> + Send + 'static) -> Self {
+        Self {
+            deregister: Box::pin(deregister),
+        }
+    }
+
+    /// Stop the heartbeat and delete the keys of this instance, the keys
+    /// shared by all replicas like the traefik router are kept as long as
+    /// other replicas are registered.
+    pub async fn deregister(self) -> Result<()> {
+        self.deregister.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_instance_ids_differ() {
+        let a = random_instance_id("service");
+        let b = random_instance_id("service");
+        assert!(a.starts_with("service-"), "{a}");
+        assert_ne!(a, b);
+    }
 }